@@ -0,0 +1,307 @@
+use std::error::Error;
+use std::io::{Read, Write};
+
+// The compiler assumes a 30,000 cell tape: the stack grows up from cell 0 and
+// 'initmem' places the 256 addressable bytes at the very end of the tape
+pub const TAPE_SIZE: usize = 30000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn max_value(&self) -> u32 {
+        match self {
+            CellWidth::U8 => u8::MAX as u32,
+            CellWidth::U16 => u16::MAX as u32,
+            CellWidth::U32 => u32::MAX,
+        }
+    }
+
+    pub fn from_bits(bits: &str) -> Option<CellWidth> {
+        match bits {
+            "8" => Some(CellWidth::U8),
+            "16" => Some(CellWidth::U16),
+            "32" => Some(CellWidth::U32),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    // When false, incrementing past the maximum or decrementing below zero is an error
+    pub wrapping: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tape_size: TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            wrapping: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Instruction {
+    Add(u32),
+    Sub(u32),
+    Right(usize),
+    Left(usize),
+    // Each jump stores the index of its matching bracket
+    JumpIfZero(usize),
+    JumpIfNonZero(usize),
+    Output,
+    Input,
+    Breakpoint,
+}
+
+// Brainf*ck source reduced to instructions, with runs of '+', '-', '<' and '>'
+// collapsed and the brackets matched ahead of time
+#[derive(Debug)]
+pub struct Code {
+    instructions: Vec<Instruction>,
+}
+
+impl Code {
+    pub fn parse(source: &str) -> Result<Code, Box<dyn Error>> {
+        let mut instructions: Vec<Instruction> = vec![];
+        let mut open_brackets: Vec<(usize, usize)> = vec![];
+
+        for (offset, ch) in source.char_indices() {
+            let previous = instructions.last_mut();
+            match (ch, previous) {
+                ('+', Some(Instruction::Add(count))) => *count += 1,
+                ('-', Some(Instruction::Sub(count))) => *count += 1,
+                ('>', Some(Instruction::Right(count))) => *count += 1,
+                ('<', Some(Instruction::Left(count))) => *count += 1,
+                ('+', _) => instructions.push(Instruction::Add(1)),
+                ('-', _) => instructions.push(Instruction::Sub(1)),
+                ('>', _) => instructions.push(Instruction::Right(1)),
+                ('<', _) => instructions.push(Instruction::Left(1)),
+                ('.', _) => instructions.push(Instruction::Output),
+                (',', _) => instructions.push(Instruction::Input),
+                ('#', _) => instructions.push(Instruction::Breakpoint),
+                ('[', _) => {
+                    open_brackets.push((instructions.len(), offset));
+                    instructions.push(Instruction::JumpIfZero(0));
+                }
+                (']', _) => {
+                    let (open, _) = open_brackets
+                        .pop()
+                        .ok_or(format!("unmatched ']' at offset {}", offset))?;
+                    instructions[open] = Instruction::JumpIfZero(instructions.len());
+                    instructions.push(Instruction::JumpIfNonZero(open));
+                }
+                _ => {
+                    // Every other character is a comment
+                }
+            }
+        }
+        if let Some((_, offset)) = open_brackets.pop() {
+            Err(format!("unmatched '[' at offset {}", offset))?;
+        }
+        Ok(Code { instructions })
+    }
+}
+
+#[derive(Debug)]
+pub struct Machine {
+    pub tape: Vec<u32>,
+    pub pointer: usize,
+    // Number of bytes written to the output so far
+    pub output_len: usize,
+    config: Config,
+}
+
+impl Machine {
+    pub fn new(config: Config) -> Machine {
+        Machine {
+            tape: vec![0; config.tape_size],
+            pointer: 0,
+            output_len: 0,
+            config,
+        }
+    }
+
+    // Runs the code to completion. Reading past the end of the input stores 0
+    // in the current cell
+    pub fn execute<R: Read, W: Write>(
+        &mut self,
+        code: &Code,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), Box<dyn Error>> {
+        self.execute_with_breakpoints(code, input, output, |_| {})
+    }
+
+    // Same as execute, but calls on_breakpoint every time a '#' is reached
+    pub fn execute_with_breakpoints<R: Read, W: Write, F: FnMut(&Machine)>(
+        &mut self,
+        code: &Code,
+        input: &mut R,
+        output: &mut W,
+        mut on_breakpoint: F,
+    ) -> Result<(), Box<dyn Error>> {
+        let max_value = self.config.cell_width.max_value() as u64;
+        let modulus = max_value + 1;
+
+        let mut i = 0;
+        while i < code.instructions.len() {
+            match code.instructions[i] {
+                Instruction::Add(count) => {
+                    let value = self.tape[self.pointer] as u64 + count as u64;
+                    if value > max_value && !self.config.wrapping {
+                        Err(format!("cell {} overflowed", self.pointer))?;
+                    }
+                    self.tape[self.pointer] = (value % modulus) as u32;
+                }
+                Instruction::Sub(count) => {
+                    let value = self.tape[self.pointer] as u64;
+                    let count = count as u64;
+                    if count > value && !self.config.wrapping {
+                        Err(format!("cell {} underflowed", self.pointer))?;
+                    }
+                    self.tape[self.pointer] =
+                        ((value + modulus - count % modulus) % modulus) as u32;
+                }
+                Instruction::Right(count) => {
+                    if self.pointer + count >= self.tape.len() {
+                        Err(format!(
+                            "pointer moved past the end of the tape (cell {})",
+                            self.pointer + count
+                        ))?;
+                    }
+                    self.pointer += count;
+                }
+                Instruction::Left(count) => {
+                    if count > self.pointer {
+                        Err("pointer moved past the start of the tape")?;
+                    }
+                    self.pointer -= count;
+                }
+                Instruction::JumpIfZero(target) => {
+                    if self.tape[self.pointer] == 0 {
+                        i = target;
+                    }
+                }
+                Instruction::JumpIfNonZero(target) => {
+                    if self.tape[self.pointer] != 0 {
+                        i = target;
+                    }
+                }
+                Instruction::Output => {
+                    output.write_all(&[self.tape[self.pointer] as u8])?;
+                    self.output_len += 1;
+                }
+                Instruction::Input => {
+                    let mut byte = [0u8; 1];
+                    self.tape[self.pointer] = match input.read(&mut byte)? {
+                        0 => 0,
+                        _ => byte[0] as u32,
+                    };
+                }
+                Instruction::Breakpoint => {
+                    on_breakpoint(self);
+                }
+            }
+            i += 1;
+        }
+        output.flush()?;
+        Ok(())
+    }
+}
+
+pub fn run<R: Read, W: Write>(
+    source: &str,
+    config: Config,
+    input: &mut R,
+    output: &mut W,
+) -> Result<Machine, Box<dyn Error>> {
+    let code = Code::parse(source)?;
+    let mut machine = Machine::new(config);
+    machine.execute(&code, input, output)?;
+    Ok(machine)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_to_string(source: &str, config: Config, input: &str) -> String {
+        let mut output: Vec<u8> = vec![];
+        run(source, config, &mut input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn hello_world() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(
+            run_to_string(source, Config::default(), ""),
+            "Hello World!\n"
+        );
+    }
+
+    #[test]
+    fn echo_until_eof() {
+        assert_eq!(run_to_string(",[.,]", Config::default(), "abc"), "abc");
+    }
+
+    #[test]
+    fn cells_wrap() {
+        let machine = run("-", Config::default(), &mut "".as_bytes(), &mut vec![]).unwrap();
+        assert_eq!(machine.tape[0], 255);
+
+        let config = Config {
+            cell_width: CellWidth::U16,
+            ..Config::default()
+        };
+        let machine = run("-", config, &mut "".as_bytes(), &mut vec![]).unwrap();
+        assert_eq!(machine.tape[0], 65535);
+    }
+
+    #[test]
+    fn non_wrapping_cells_error() {
+        let config = Config {
+            wrapping: false,
+            ..Config::default()
+        };
+        assert!(run("-", config, &mut "".as_bytes(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let code = Code::parse("+#>++#.").unwrap();
+        let mut machine = Machine::new(Config::default());
+        let mut snapshots: Vec<(usize, u32)> = vec![];
+        machine
+            .execute_with_breakpoints(&code, &mut "".as_bytes(), &mut vec![], |machine| {
+                snapshots.push((machine.pointer, machine.tape[machine.pointer]));
+            })
+            .unwrap();
+        assert_eq!(snapshots, vec![(0, 1), (1, 2)]);
+        assert_eq!(machine.output_len, 1);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert!(Code::parse("[[]").is_err());
+        assert!(Code::parse("[]]").is_err());
+    }
+
+    #[test]
+    fn pointer_bounds() {
+        let config = Config::default();
+        assert!(run("<", config.clone(), &mut "".as_bytes(), &mut vec![]).is_err());
+        let source = ">".repeat(TAPE_SIZE);
+        assert!(run(&source, config, &mut "".as_bytes(), &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::diagnostic::Diagnostic;
+use crate::{Function, Location, Program, Signature};
+use std::collections::HashMap;
+use std::error::Error;
+
+// The number of values a function takes from the top of the stack and the number it leaves in
+// their place. None when this depends on the input, for example a loop over a string
+pub type StackEffect = Option<(usize, usize)>;
+
+// A block that has been opened but not yet closed, with the stack height at its start
+struct Block<'a> {
+    function: &'a Function,
+    index: usize,
+    height: i64,
+    baseline: usize,
+}
+
+struct Checker<'a> {
+    program: &'a Program,
+    effects: HashMap<&'a str, StackEffect>,
+}
+
+// Infers the stack effect of every function and checks that:
+//  - each branch of an if/else block and each while loop body leaves the stack height unchanged
+//  - main never takes more values from the stack than it holds
+//  - a function with a signature matches it, and never takes more values than it declares
+// Loops over strings are the exception: a function whose signature takes or leaves a 'str' or a
+// 'list' may have while loops that change the stack height, but the height after such a loop is
+// unknown so nothing that follows it can be checked for underflow
+pub fn check_program(program: &Program) -> Result<(), Box<dyn Error>> {
+    stack_effects(program)?;
+    Ok(())
+}
+
+// Runs the same checks as check_program and returns the stack effect of every function. A
+// function with a signature has the effect it declares
+pub fn stack_effects(program: &Program) -> Result<HashMap<String, StackEffect>, Box<dyn Error>> {
+    let mut checker = Checker {
+        program,
+        effects: HashMap::new(),
+    };
+
+    // Sorted so the same error is reported first every time
+    let mut names: Vec<&String> = program.functions.keys().collect();
+    names.sort();
+    for name in names {
+        checker.infer(name)?;
+    }
+    Ok(checker
+        .effects
+        .into_iter()
+        .map(|(name, effect)| (name.to_string(), effect))
+        .collect())
+}
+
+impl<'a> Checker<'a> {
+    fn infer(&mut self, name: &'a str) -> Result<StackEffect, Box<dyn Error>> {
+        if let Some(effect) = self.effects.get(name) {
+            return Ok(*effect);
+        }
+        // A recursive call sees the function as having an unknown effect
+        self.effects.insert(name, None);
+
+        let function = &self.program.functions[name];
+        let signature = self.program.signatures.get(name);
+        let declared = signature.and_then(Signature::stack_effect);
+        let loops_over_strings = signature.is_some_and(Signature::names_string);
+        // What errors call the function, see Program::display_name
+        let display = self.program.display_name(name);
+
+        // The number of values on the stack when the function starts, if that is known
+        let start = match declared {
+            _ if name == "main" => Some(0),
+            Some((inputs, _)) => Some(inputs as i64),
+            None => None,
+        };
+
+        // The height is relative to a baseline. Baseline 0 is the start of the function, every
+        // time the height becomes unknown a new baseline is started
+        let mut height: i64 = start.unwrap_or(0);
+        let mut baseline: usize = 0;
+        let mut next_baseline: usize = 1;
+        let mut lowest: i64 = height;
+        let mut blocks: Vec<Block> = vec![];
+
+        for (i, operation) in function.iter().enumerate() {
+            let closes_while = matches!(operation, Function::End(_))
+                && blocks
+                    .last()
+                    .is_some_and(|block| matches!(block.function, Function::While(_)));
+
+            match operation {
+                Function::Else(_) => {
+                    if let Some(block) = blocks.pop() {
+                        if block.baseline == baseline && block.height != height {
+                            let change = height - block.height;
+                            Err(unbalanced_block(
+                                self.location(name, block.index),
+                                "if",
+                                change,
+                            ))?;
+                        }
+                        height = block.height;
+                        baseline = block.baseline;
+                        blocks.push(Block {
+                            function: operation,
+                            index: i,
+                            ..block
+                        });
+                    }
+                    continue;
+                }
+                Function::End(_) if !closes_while => {
+                    if let Some(block) = blocks.pop() {
+                        let kind = match block.function {
+                            Function::Else(_) => "else",
+                            _ => "if",
+                        };
+                        if block.baseline != baseline {
+                            baseline = next_baseline;
+                            next_baseline += 1;
+                            height = 0;
+                        } else if block.height != height {
+                            let change = height - block.height;
+                            Err(unbalanced_block(
+                                self.location(name, block.index),
+                                kind,
+                                change,
+                            ))?;
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            // The condition of a block is read from the top of the stack
+            let effect = match operation {
+                Function::If(_) | Function::While(_) | Function::End(_) => Some((1, 1)),
+                Function::FunctionCall(callee) => {
+                    match self.program.functions.get_key_value(callee) {
+                        Some((callee, _)) => {
+                            let inferred = self.infer(callee)?;
+                            // A recursive call only knows the effect from the signature
+                            match self.program.signatures.get(callee) {
+                                Some(signature) => signature.stack_effect(),
+                                None => inferred,
+                            }
+                        }
+                        None => None,
+                    }
+                }
+                _ => operation.stack_effect(),
+            };
+
+            match effect {
+                Some((inputs, outputs)) => {
+                    let inputs = inputs as i64;
+                    if baseline == 0 {
+                        if start.is_some() && height < inputs {
+                            let error = match operation {
+                                Function::FunctionCall(callee) => {
+                                    let shown = self.program.display_name(callee);
+                                    let error = Diagnostic::error(format!(
+                                        "'{}' calls '{}', which takes {} value(s), with {} on the stack",
+                                        display, shown, inputs, height
+                                    ));
+                                    match self.program.declarations.get(callee) {
+                                        Some(declaration) => error.with_secondary(
+                                            declaration.clone(),
+                                            format!("'{}' is declared here", shown),
+                                        ),
+                                        None => error,
+                                    }
+                                }
+                                _ => Diagnostic::error(format!(
+                                    "stack underflow in '{}': needs {} value(s) but the stack has {}",
+                                    display, inputs, height
+                                )),
+                            };
+                            Err(error.at_some(self.location(name, i)))?;
+                        }
+                        lowest = lowest.min(height - inputs);
+                    }
+                    height = height - inputs + outputs as i64;
+                }
+                None => {
+                    baseline = next_baseline;
+                    next_baseline += 1;
+                    height = 0;
+                }
+            }
+
+            match operation {
+                Function::If(_) | Function::While(_) => blocks.push(Block {
+                    function: operation,
+                    index: i,
+                    height,
+                    baseline,
+                }),
+                Function::End(_) => {
+                    if let Some(block) = blocks.pop()
+                        && (block.baseline != baseline || block.height != height)
+                    {
+                        if block.baseline == baseline && !loops_over_strings {
+                            let change = height - block.height;
+                            Err(unbalanced_block(
+                                self.location(name, block.index),
+                                "while",
+                                change,
+                            ))?;
+                        }
+                        baseline = next_baseline;
+                        next_baseline += 1;
+                        height = 0;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let inferred = if baseline == 0 {
+            let start = start.unwrap_or(0);
+            Some(((start - lowest) as usize, (height - lowest) as usize))
+        } else {
+            None
+        };
+        if let (Some(signature), Some((_, outputs))) = (signature, declared)
+            && baseline == 0
+            && height != outputs as i64
+        {
+            Err(Diagnostic::error(format!(
+                "'{}' is declared as {} but leaves {} value(s)",
+                display, signature, height
+            ))
+            .at_some(self.declaration(name)))?;
+        }
+
+        // Callers rely on the signature when there is one
+        let effect = match signature {
+            Some(signature) => signature.stack_effect(),
+            None => inferred,
+        };
+        self.effects.insert(name, effect);
+        Ok(effect)
+    }
+}
+
+impl Checker<'_> {
+    // Where the operation at index in the named function was written
+    fn location(&self, name: &str, index: usize) -> Option<Location> {
+        self.program
+            .locations
+            .get(name)
+            .and_then(|locations| locations.get(index))
+            .cloned()
+            .or_else(|| self.declaration(name))
+    }
+
+    fn declaration(&self, name: &str) -> Option<Location> {
+        self.program.declarations.get(name).cloned()
+    }
+}
+
+fn unbalanced_block(location: Option<Location>, kind: &str, change: i64) -> Diagnostic {
+    Diagnostic::error(format!(
+        "'{}' block changes the stack height by {:+}, blocks must leave the stack height unchanged",
+        kind, change
+    ))
+    .at_some(location)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_program_structure, parse_tokens, tokenize_source_code};
+
+    fn check_source(source: &str) -> Result<(), Box<dyn Error>> {
+        let tokens = tokenize_source_code("test", source);
+        let program = parse_program_structure(parse_tokens(tokens)?)?;
+        check_program(&program)
+    }
+
+    fn effect_of(source: &str, name: &str) -> StackEffect {
+        let tokens = tokenize_source_code("test", source);
+        let program = parse_program_structure(parse_tokens(tokens).unwrap()).unwrap();
+        stack_effects(&program).unwrap()[name]
+    }
+
+    #[test]
+    fn infers_function_effects() {
+        let source = "fn add3\n + +\nend\nfn double\n dup add3\nend";
+        assert_eq!(effect_of(source, "add3"), Some((3, 1)));
+        assert_eq!(effect_of(source, "double"), Some((2, 1)));
+        assert_eq!(
+            effect_of("fn f\n if 2 + else 3 * end\nend", "f"),
+            Some((1, 1))
+        );
+        assert_eq!(
+            effect_of("fn f ( str -- )\n while chout end pop\nend", "f"),
+            None
+        );
+    }
+
+    #[test]
+    fn reports_underflow_in_main() {
+        assert!(check_source("fn main\n 1 2 + pop\nend").is_ok());
+        let err = check_source("fn main\n 1 2 + + pop\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:2:8:"), "{}", err);
+
+        let err = check_source("fn take\n pop pop\nend\nfn main\n 1 take\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:5:4:"), "{}", err);
+    }
+
+    #[test]
+    fn blocks_must_be_balanced() {
+        assert!(check_source("fn main\n 1 if 2 pop else 3 pop end\nend").is_ok());
+        assert!(check_source("fn main\n 1 while 1 - end\nend").is_ok());
+        assert!(check_source("fn main\n 1 while dup chout 1 - end pop\nend").is_ok());
+
+        let err = check_source("fn main\n 1 if 2 end\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:2:4:"), "{}", err);
+
+        let err = check_source("fn main\n 1 if 2 pop else pop end\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:2:13:"), "{}", err);
+
+        let err = check_source("fn main\n 3 while 1 - dup end\nend").unwrap_err();
+        assert!(
+            err.to_string().starts_with("test:2:4: 'while' block"),
+            "{}",
+            err
+        );
+        let err = check_source("fn f\n while swap chout 1 - end\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:2:2:"), "{}", err);
+
+        // The original examples/while.phron, its if block prints a character
+        let source = "fn main\n 74 while\n dup\n 1 if\n swap\n chout\n end\n pop\n 1 -\n end\nend";
+        let err = check_source(source).unwrap_err();
+        assert!(
+            err.to_string().starts_with("test:4:4: 'if' block"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn loops_over_strings_make_the_height_unknown() {
+        // The string is printed until the NULL character, after that the height is unknown
+        let show = "fn show ( str -- )\n while chout end";
+        assert!(check_source(&format!("{show} pop\nend")).is_ok());
+        assert!(check_source(&format!("{show} 1 if 2 end\nend")).is_err());
+        // Only a function that names a 'str' in its signature may loop over one
+        assert!(check_source("fn main\n \"hi\" while chout end pop\nend").is_err());
+        // So may one that names a counted list
+        let count = "fn count ( list -- n )\n while swap pop 1 - end\nend";
+        assert!(check_source(count).is_ok());
+    }
+
+    #[test]
+    fn functions_match_their_signatures() {
+        assert!(check_source("fn add ( a b -- c )\n +\nend").is_ok());
+        assert!(check_source("fn keep ( a b -- a b c )\n 1\nend").is_ok());
+        assert!(check_source("fn show ( str -- )\n while chout end pop\nend").is_ok());
+
+        let err = check_source("fn f ( a -- a b )\n 1 +\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:1:1:"), "{}", err);
+
+        let err = check_source("fn f ( a -- b )\n +\nend").unwrap_err();
+        assert!(err.to_string().starts_with("test:2:2:"), "{}", err);
+
+        assert!(check_source("fn f ( a b\n +\nend").is_err());
+        assert!(check_source("fn f ( a -- b -- c )\nend").is_err());
+    }
+
+    #[test]
+    fn calls_are_checked_against_signatures() {
+        let add = "fn add ( a b -- c )\n +\nend\n";
+        assert!(check_source(&format!("{add}fn main\n 1 2 add pop\nend")).is_ok());
+
+        let err = check_source(&format!("{add}fn main\n 1 add\nend")).unwrap_err();
+        assert!(err.to_string().contains("'main' calls 'add'"), "{}", err);
+
+        let err = check_source(&format!("{add}fn f ( a -- b )\n add\nend")).unwrap_err();
+        assert!(err.to_string().contains("'f' calls 'add'"), "{}", err);
+    }
+}
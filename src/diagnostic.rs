@@ -0,0 +1,228 @@
+use crate::Location;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// A problem found in a program, pointing at the code that caused it. The primary location is
+// underlined with '^', each secondary location with '-' and its label
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub primary: Option<Location>,
+    pub secondary: Vec<(Location, String)>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            primary: None,
+            secondary: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message)
+        }
+    }
+
+    pub fn at(mut self, location: Location) -> Diagnostic {
+        self.primary = Some(location);
+        self
+    }
+
+    // Points at the location when it is known
+    pub fn at_some(mut self, location: Option<Location>) -> Diagnostic {
+        self.primary = location;
+        self
+    }
+
+    pub fn with_secondary(mut self, location: Location, label: impl Into<String>) -> Diagnostic {
+        self.secondary.push((location, label.into()));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    // Writes the diagnostic with the source line of each location and a caret underline. source
+    // returns the contents of a file, a location in a file it can't read is written without its
+    // line
+    pub fn render(&self, source: &dyn Fn(&str) -> Option<String>) -> String {
+        let mut rendered = format!("{}: {}\n", self.severity, self.message);
+        let locations = self
+            .primary
+            .iter()
+            .map(|location| (location, '^', ""))
+            .chain(
+                self.secondary
+                    .iter()
+                    .map(|(location, label)| (location, '-', label.as_str())),
+            );
+        let width = self
+            .primary
+            .iter()
+            .chain(self.secondary.iter().map(|(location, _)| location))
+            .map(|location| location.row.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(width);
+
+        for (location, underline, label) in locations {
+            rendered.push_str(&format!("{}--> {}\n", gutter, location));
+            let line = source(&location.filepath).and_then(|source| {
+                source
+                    .lines()
+                    .nth(location.row.wrapping_sub(1))
+                    .map(str::to_string)
+            });
+            let Some(line) = line else {
+                continue;
+            };
+            // The column counts bytes, the underline is placed by characters
+            let start = line
+                .get(..location.col.saturating_sub(1))
+                .map_or(0, |before| before.chars().count());
+            rendered.push_str(&format!("{} |\n", gutter));
+            rendered.push_str(&format!("{:>width$} | {}\n", location.row, line));
+            let mut marker = format!(
+                "{} | {}{}",
+                gutter,
+                " ".repeat(start),
+                underline.to_string().repeat(location.length.max(1))
+            );
+            if !label.is_empty() {
+                marker.push(' ');
+                marker.push_str(label);
+            }
+            rendered.push_str(&marker);
+            rendered.push('\n');
+        }
+        for note in &self.notes {
+            rendered.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.primary {
+            Some(location) => write!(f, "{}: {}", location, self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        for note in &self.notes {
+            write!(f, "\n  = note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostic {}
+
+// Every problem found by a pass that reports more than one
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(Diagnostic::to_string).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Error for Diagnostics {}
+
+// Renders an error from any part of the pipeline, with source lines if it is a diagnostic
+pub fn render_error(
+    err: &(dyn Error + 'static),
+    source: &dyn Fn(&str) -> Option<String>,
+) -> String {
+    if let Some(diagnostic) = err.downcast_ref::<Diagnostic>() {
+        diagnostic.render(source)
+    } else if let Some(diagnostics) = err.downcast_ref::<Diagnostics>() {
+        let rendered: Vec<String> = diagnostics
+            .0
+            .iter()
+            .map(|diagnostic| diagnostic.render(source))
+            .collect();
+        rendered.join("\n")
+    } else {
+        format!("{}: {}\n", Severity::Error, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(row: usize, col: usize, length: usize) -> Location {
+        Location {
+            filepath: String::from("test"),
+            row,
+            col,
+            length,
+        }
+    }
+
+    #[test]
+    fn renders_source_lines() {
+        let source = |_: &str| Some(String::from("fn main\n 1 doubel\nend"));
+        let diagnostic = Diagnostic::error("unknown word 'doubel'")
+            .at(location(2, 4, 6))
+            .with_secondary(location(1, 4, 4), "in this function")
+            .with_note("did you mean 'double'?");
+        assert_eq!(
+            diagnostic.render(&source),
+            "error: unknown word 'doubel'\n \
+             --> test:2:4\n  \
+             |\n\
+             2 |  1 doubel\n  \
+             |    ^^^^^^\n \
+             --> test:1:4\n  \
+             |\n\
+             1 | fn main\n  \
+             |    ---- in this function\n  \
+             = note: did you mean 'double'?\n"
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "test:2:4: unknown word 'doubel'\n  = note: did you mean 'double'?"
+        );
+    }
+
+    #[test]
+    fn renders_without_source() {
+        let diagnostic = Diagnostic::warning("memory is initialized twice").at(location(3, 2, 7));
+        assert_eq!(
+            diagnostic.render(&|_| None),
+            "warning: memory is initialized twice\n --> test:3:2\n"
+        );
+        assert_eq!(
+            Diagnostic::error("no location").render(&|_| None),
+            "error: no location\n"
+        );
+    }
+}
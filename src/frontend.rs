@@ -0,0 +1,588 @@
+use crate::check;
+use crate::diagnostic::Diagnostic;
+use crate::{
+    Function, Location, Program, closest_word, create_references_for_blocks,
+    parse_program_structure, parse_tokens, resolve_calls, resolve_variables, tokenize_source_code,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Reads a program and everything it imports, ready to be simulated or compiled. Imports are
+// searched for in the directories of search_path after the directory of the file importing them
+pub fn read_program(filepath: &str, search_path: &[PathBuf]) -> Result<Program, Box<dyn Error>> {
+    let source = fs::read_to_string(filepath)?;
+    parse_program(filepath, &source, search_path)
+}
+
+// The same as read_program with the source of the file already read. Imports in a source that
+// isn't from a file are found relative to the current directory
+pub fn parse_program(
+    filepath: &str,
+    source: &str,
+    search_path: &[PathBuf],
+) -> Result<Program, Box<dyn Error>> {
+    let tokens = tokenize_source_code(filepath, source);
+    let parsed_tokens = parse_tokens(tokens)?;
+    let program = parse_program_structure(parsed_tokens)?;
+    let mut program = handle_imports(program, filepath, search_path)?;
+    resolve_variables(&mut program)?;
+    resolve_calls(&program)?;
+    if !program.functions.contains_key("main") {
+        Err(Diagnostic::error(format!(
+            "could not find function main in {}",
+            filepath
+        )))?;
+    }
+    check::check_program(&program)?;
+    link_blocks(&mut program)?;
+    Ok(program)
+}
+
+// Links the blocks of every function, once all of its operations are known
+fn link_blocks(program: &mut Program) -> Result<(), Box<dyn Error>> {
+    for (name, fblock) in program.functions.iter_mut() {
+        let locations = program.locations.get(name).map_or(&[][..], Vec::as_slice);
+        create_references_for_blocks(fblock, locations)?;
+    }
+    Ok(())
+}
+
+// Every import read so far, from the canonical path of the importing file to the canonical path
+// of each file it imports, with where the import was written and the path the file was found at
+type ImportGraph = HashMap<PathBuf, Vec<(PathBuf, Location, String)>>;
+
+// A file of the program, with the files it imports under a name
+struct File {
+    filepath: String,
+    program: Program,
+    // The index of each file imported with 'import path as name', by name
+    aliases: HashMap<String, usize>,
+}
+
+// What a function or variable of a file is called in the whole program. Those of the program's
+// own file and of files imported without a name keep their names, unless they are private. Every
+// other one is prefixed with the path of its file, so it can't clash with one of the same name in
+// another file. It is still reported by the name it is written as: its own name if it is private,
+// or 'name::function' with the name its file was first imported as
+struct Name {
+    internal: String,
+    display: String,
+    private: bool,
+    declaration: Option<Location>,
+    // Variables are also used as 'name@' and 'name!'
+    variable: bool,
+}
+
+// Loads each imported file once, however many files import it, and merges them into one program.
+// A file that imports itself, directly or through other files, is an import cycle
+fn handle_imports(
+    mut program: Program,
+    filepath: &str,
+    search_path: &[PathBuf],
+) -> Result<Program, Box<dyn Error>> {
+    let mut imports: VecDeque<(String, Option<String>, Location)> =
+        program.imports.drain(..).collect();
+    let mut files: Vec<File> = vec![File {
+        filepath: filepath.to_string(),
+        program,
+        aliases: HashMap::new(),
+    }];
+    // The index in files of every file that has been loaded
+    let mut loaded: HashMap<PathBuf, usize> = HashMap::new();
+    loaded.insert(canonical(Path::new(filepath)), 0);
+    // The files imported without a name at least once
+    let mut unnamed: HashSet<usize> = HashSet::from([0]);
+    // The name each file imported with a name was first imported as
+    let mut first_alias: HashMap<usize, String> = HashMap::new();
+    let mut graph: ImportGraph = HashMap::new();
+
+    while let Some((import, alias, location)) = imports.pop_front() {
+        let filepath = resolve_import(&import, &location, search_path)?;
+        let filepath = filepath.to_string_lossy().to_string();
+        let imported = fs::canonicalize(&filepath)?;
+        let importer = canonical(Path::new(&location.filepath));
+        // A cycle is found by the import that completes it, which is read after the others
+        if let Some(chain) = find_imports(&graph, &imported, &importer, &mut HashSet::new()) {
+            let mut files: Vec<&str> = vec![&filepath];
+            files.extend(chain.iter().map(|(_, _, filepath)| filepath.as_str()));
+            files.push(&filepath);
+            let mut error = Diagnostic::error(format!("import cycle: {}", files.join(" -> ")))
+                .at(location.clone());
+            for (_, import, filepath) in chain {
+                error = error.with_secondary(import.clone(), format!("imports {}", filepath));
+            }
+            Err(error)?;
+        }
+        graph.entry(importer.clone()).or_default().push((
+            imported.clone(),
+            location.clone(),
+            filepath.clone(),
+        ));
+
+        let index = match loaded.get(&imported) {
+            Some(&index) => index,
+            None => {
+                let source = fs::read_to_string(&filepath)?;
+                let tokens = tokenize_source_code(&filepath, &source);
+                let parsed_tokens = parse_tokens(tokens)?;
+                let mut import_program = parse_program_structure(parsed_tokens)?;
+                imports.extend(import_program.imports.drain(..));
+                files.push(File {
+                    filepath,
+                    program: import_program,
+                    aliases: HashMap::new(),
+                });
+                loaded.insert(imported, files.len() - 1);
+                files.len() - 1
+            }
+        };
+        match alias {
+            Some(alias) => {
+                let aliases = &mut files[loaded[&importer]].aliases;
+                if aliases
+                    .insert(alias.clone(), index)
+                    .is_some_and(|other| other != index)
+                {
+                    Err(Diagnostic::error(format!(
+                        "'{}' is already the name of another import",
+                        alias
+                    ))
+                    .at(location))?;
+                }
+                first_alias.entry(index).or_insert(alias);
+            }
+            None => {
+                unnamed.insert(index);
+            }
+        }
+    }
+
+    let names: Vec<HashMap<String, Name>> = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let program = &file.program;
+            let functions = program
+                .functions
+                .keys()
+                .map(|name| (name, program.declarations.get(name), false));
+            let variables = program
+                .variables
+                .iter()
+                .map(|(name, _, location)| (name, Some(location), true));
+            functions
+                .chain(variables)
+                .map(|(name, declaration, variable)| {
+                    let private = program.private.contains(name);
+                    let prefixed = format!("{}::{}", file.filepath, name);
+                    let (internal, display) = if private {
+                        (prefixed, name.clone())
+                    } else if unnamed.contains(&i) {
+                        (name.clone(), name.clone())
+                    } else {
+                        (prefixed, format!("{}::{}", first_alias[&i], name))
+                    };
+                    let qualified = Name {
+                        internal,
+                        display,
+                        private,
+                        declaration: declaration.cloned(),
+                        variable,
+                    };
+                    (name.clone(), qualified)
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut files = files.into_iter().enumerate();
+    let (_, root) = files.next().unwrap();
+    let mut program = qualify(root, 0, &names)?;
+    for (i, file) in files {
+        program.consume(qualify(file, i, &names)?)?;
+    }
+    Ok(program)
+}
+
+// Renames the functions and variables of a file to what they are called in the whole program,
+// along with every use of them. A word in the file is a function or variable of the file if there
+// is one, 'name::word' is one of the file imported as name, and any other word is left to be
+// found once every file has been merged
+fn qualify(
+    file: File,
+    index: usize,
+    names: &[HashMap<String, Name>],
+) -> Result<Program, Box<dyn Error>> {
+    let File {
+        mut program,
+        aliases,
+        ..
+    } = file;
+    let own = &names[index];
+
+    for (function_name, function) in program.functions.iter_mut() {
+        for (i, operation) in function.iter_mut().enumerate() {
+            let Function::FunctionCall(word) = operation else {
+                continue;
+            };
+            let location = program
+                .locations
+                .get(function_name)
+                .and_then(|locations| locations.get(i))
+                .cloned();
+            if let Some((name, suffix)) = find_name(own, word) {
+                *word = format!("{}{}", name.internal, suffix);
+                continue;
+            }
+            let Some((alias, name)) = word.split_once("::") else {
+                continue;
+            };
+            let Some(&imported) = aliases.get(alias) else {
+                continue;
+            };
+            match find_name(&names[imported], name) {
+                Some((name, suffix)) if !name.private => {
+                    *word = format!("{}{}", name.internal, suffix)
+                }
+                Some((name, _)) => {
+                    let error = Diagnostic::error(format!(
+                        "'{}' is private to the file it is declared in",
+                        word
+                    ))
+                    .at_some(location);
+                    Err(match &name.declaration {
+                        Some(declaration) => {
+                            error.with_secondary(declaration.clone(), "declared private here")
+                        }
+                        None => error,
+                    })?;
+                }
+                None => {
+                    let public: Vec<String> = names[imported]
+                        .iter()
+                        .filter(|(_, name)| !name.private)
+                        .flat_map(|(declared, name)| {
+                            let suffixes: &[&str] = if name.variable {
+                                &["", "@", "!"]
+                            } else {
+                                &[""]
+                            };
+                            suffixes
+                                .iter()
+                                .map(move |suffix| format!("{}::{}{}", alias, declared, suffix))
+                        })
+                        .collect();
+                    let public: Vec<&str> = public.iter().map(String::as_str).collect();
+                    let error =
+                        Diagnostic::error(format!("unknown word '{}'", word)).at_some(location);
+                    Err(match closest_word(word, &public) {
+                        Some(closest) => error.with_note(format!("did you mean '{}'?", closest)),
+                        None => error,
+                    })?;
+                }
+            }
+        }
+    }
+
+    let internal = |name: String| match own.get(&name) {
+        Some(own) => own.internal.clone(),
+        None => name,
+    };
+    program.functions = program
+        .functions
+        .into_iter()
+        .map(|(name, function)| (internal(name), function))
+        .collect();
+    program.locations = program
+        .locations
+        .into_iter()
+        .map(|(name, locations)| (internal(name), locations))
+        .collect();
+    program.declarations = program
+        .declarations
+        .into_iter()
+        .map(|(name, declaration)| (internal(name), declaration))
+        .collect();
+    program.signatures = program
+        .signatures
+        .into_iter()
+        .map(|(name, signature)| (internal(name), signature))
+        .collect();
+    program.variables = program
+        .variables
+        .into_iter()
+        .map(|(name, size, location)| (internal(name), size, location))
+        .collect();
+    program.overrides = program.overrides.into_iter().map(internal).collect();
+    program.private = program.private.into_iter().map(internal).collect();
+    program.names = own
+        .values()
+        .filter(|name| name.internal != name.display)
+        .map(|name| (name.internal.clone(), name.display.clone()))
+        .collect();
+    Ok(program)
+}
+
+// The function or variable a word of a file refers to, with the '@' or '!' after a variable
+fn find_name<'a, 'b>(
+    names: &'a HashMap<String, Name>,
+    word: &'b str,
+) -> Option<(&'a Name, &'b str)> {
+    if let Some(name) = names.get(word) {
+        return Some((name, ""));
+    }
+    let base = word.strip_suffix(['@', '!'])?;
+    let name = names.get(base).filter(|name| name.variable)?;
+    Some((name, &word[base.len()..]))
+}
+
+// The path that identifies a file however it was named. A source that isn't from a file keeps the
+// name it was given
+fn canonical(filepath: &Path) -> PathBuf {
+    fs::canonicalize(filepath).unwrap_or_else(|_| filepath.to_path_buf())
+}
+
+// The imports that lead from one file to another, in order, if there are any. A file leads to
+// itself without any imports
+fn find_imports<'a>(
+    graph: &'a ImportGraph,
+    from: &Path,
+    to: &Path,
+    visited: &mut HashSet<PathBuf>,
+) -> Option<Vec<&'a (PathBuf, Location, String)>> {
+    if from == to {
+        return Some(vec![]);
+    }
+    if !visited.insert(from.to_path_buf()) {
+        return None;
+    }
+    for import in graph.get(from).into_iter().flatten() {
+        if let Some(mut chain) = find_imports(graph, &import.0, to, visited) {
+            chain.insert(0, import);
+            return Some(chain);
+        }
+    }
+    None
+}
+
+// Finds the file named by an import, relative to the directory of the file it was written in or
+// else in each directory of the search path in turn
+fn resolve_import(
+    import: &str,
+    location: &Location,
+    search_path: &[PathBuf],
+) -> Result<PathBuf, Box<dyn Error>> {
+    let directory = Path::new(&location.filepath)
+        .parent()
+        .unwrap_or(Path::new(""));
+    let candidates: Vec<PathBuf> = std::iter::once(directory)
+        .chain(search_path.iter().map(PathBuf::as_path))
+        // Collecting the components drops any '.' in the middle of the path
+        .map(|directory| directory.join(import).components().collect())
+        .collect();
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(filepath) => Ok(filepath.clone()),
+        None => {
+            let searched: Vec<String> = candidates
+                .iter()
+                .map(|candidate| candidate.display().to_string())
+                .collect();
+            Err(
+                Diagnostic::error(format!("cannot find imported file '{}'", import))
+                    .at(location.clone())
+                    .with_note(format!("looked for {}", searched.join(", "))),
+            )?
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn imports_are_loaded_once() {
+        let directory = TempDir::new("cycles");
+        let write = |name: &str, source: &str| directory.write(name, source);
+        let read = || read_program(&directory.display("main.phron"), &[]);
+        // Both a and b import c, which would define 'c' twice if it was loaded twice
+        write(
+            "main.phron",
+            "import a.phron\nimport b.phron\nfn main\n a b\nend",
+        );
+        write("a.phron", "import c.phron\nfn a\n c\nend");
+        write("b.phron", "import ./c.phron\nfn b\n c\nend");
+        write("c.phron", "fn c\nend");
+        assert!(read().is_ok());
+
+        let display = |name: &str| directory.display(name);
+        write("c.phron", "import b.phron\nfn c\nend");
+        let err = read().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:1:1: import cycle: {} -> {} -> {}",
+                display("c.phron"),
+                display("b.phron"),
+                display("c.phron"),
+                display("b.phron")
+            )
+        );
+
+        write("c.phron", "import c.phron\nfn c\nend");
+        let err = read().unwrap_err();
+        assert!(err.to_string().ends_with(&format!(
+            "import cycle: {} -> {}",
+            display("c.phron"),
+            display("c.phron")
+        )));
+    }
+
+    #[test]
+    fn imports_can_be_named() {
+        let directory = TempDir::new("names");
+        let write = |name: &str, source: &str| directory.write(name, source);
+        let read = || read_program(&directory.display("main.phron"), &[]);
+        // Each file has its own private helper, and main has a function of the same name
+        write(
+            "math.phron",
+            "private fn helper\n 2 *\nend\nfn double\n helper\nend",
+        );
+        write(
+            "count.phron",
+            "private fn helper\n 1 +\nend\nfn inc\n helper\nend",
+        );
+        write(
+            "main.phron",
+            "import math.phron as math\nimport count.phron\nfn helper\nend\nfn main\n 3 math::double inc helper\nend",
+        );
+        let program = read().unwrap();
+        let math = directory.display("math.phron");
+        let count = directory.display("count.phron");
+        assert_eq!(
+            program.functions["main"],
+            vec![
+                Function::Push(3),
+                Function::FunctionCall(format!("{}::double", math)),
+                Function::FunctionCall(String::from("inc")),
+                Function::FunctionCall(String::from("helper")),
+            ]
+        );
+        assert_eq!(
+            program.functions[&format!("{}::double", math)],
+            vec![Function::FunctionCall(format!("{}::helper", math))]
+        );
+        assert_eq!(
+            program.functions["inc"],
+            vec![Function::FunctionCall(format!("{}::helper", count))]
+        );
+
+        let error = |main: &str| {
+            write("main.phron", main);
+            read().unwrap_err().to_string()
+        };
+        let main = directory.display("main.phron");
+        assert_eq!(
+            error("import math.phron as math\nfn main\n 3 math::helper\nend"),
+            format!(
+                "{}:3:4: 'math::helper' is private to the file it is declared in",
+                main
+            )
+        );
+        assert_eq!(
+            error("import math.phron as math\nfn main\n 3 math::doubel\nend"),
+            format!(
+                "{}:3:4: unknown word 'math::doubel'\n  = note: did you mean 'math::double'?",
+                main
+            )
+        );
+        // Errors name the function as it is written, not by the path it is known by internally
+        write(
+            "main.phron",
+            "import math.phron as math\nfn main\n math::double\nend",
+        );
+        let err = read().unwrap_err();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(
+            diagnostic.message,
+            "'main' calls 'math::double', which takes 1 value(s), with 0 on the stack"
+        );
+        assert_eq!(diagnostic.secondary[0].1, "'math::double' is declared here");
+        // Only the name the file is imported as reaches its functions
+        assert!(
+            error("import math.phron as math\nfn main\n 3 double\nend")
+                .ends_with("unknown word 'double'")
+        );
+        assert!(
+            error("import count.phron\nfn main\n 3 helper\nend").ends_with("unknown word 'helper'")
+        );
+        assert!(
+            error("import math.phron as m\nimport count.phron as m\nfn main\nend")
+                .contains("'m' is already the name of another import")
+        );
+    }
+
+    #[test]
+    fn variables_of_named_imports_are_qualified() {
+        let directory = TempDir::new("variables");
+        let write = |name: &str, source: &str| directory.write(name, source);
+        let read = || read_program(&directory.display("main.phron"), &[]);
+        // Both files have a variable of the same name, and one has a private variable
+        write(
+            "a.phron",
+            "var tmp\nprivate var hidden\nfn set ( x -- )\n tmp! 1 hidden!\nend",
+        );
+        write("b.phron", "var tmp 2\nfn get ( -- x )\n tmp@\nend");
+        write(
+            "main.phron",
+            "import a.phron as a\nimport b.phron as b\nfn main\n initmem 5 a::set 6 a::tmp! a::tmp@ b::tmp b::get\nend",
+        );
+        let program = read().unwrap();
+        let a = directory.display("a.phron");
+        let b = directory.display("b.phron");
+        let variables: Vec<(String, usize)> = program
+            .variables
+            .iter()
+            .map(|(name, size, _)| (name.clone(), *size))
+            .collect();
+        assert_eq!(
+            variables,
+            vec![
+                (format!("{}::tmp", a), 1),
+                (format!("{}::hidden", a), 1),
+                (format!("{}::tmp", b), 2),
+            ]
+        );
+        assert_eq!(
+            program.functions["main"][3..],
+            [
+                Function::Push(6),
+                Function::Push(255),
+                Function::Swap(),
+                Function::Write(),
+                Function::Push(255),
+                Function::Read(),
+                Function::Push(252),
+                Function::FunctionCall(format!("{}::get", b)),
+            ]
+        );
+
+        let error = |main: &str| {
+            write("main.phron", main);
+            read().unwrap_err().to_string()
+        };
+        // Only the name the file is imported as reaches its variables
+        assert!(error("import a.phron as a\nfn main\n tmp@\nend").ends_with("unknown word 'tmp@'"));
+        assert!(
+            error("import a.phron as a\nfn main\n a::hidden@\nend")
+                .ends_with("'a::hidden@' is private to the file it is declared in")
+        );
+        assert!(
+            error("import a.phron as a\nfn main\n a::tpm!\nend")
+                .ends_with("did you mean 'a::tmp!'?")
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+// The compiler generates this structured form of brainf*ck, which is optimized and then written
+// out as brainf*ck or C
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    // Adds to the current cell, a negative count subtracts
+    Add(i64),
+    // Moves the pointer, a negative count moves it left
+    Move(i64),
+    Loop(Vec<Node>),
+    // Sets the current cell to 0
+    Clear,
+    Output,
+    Input,
+    Breakpoint,
+}
+
+// Builds a list of nodes one operation at a time. Loops can be opened and closed separately, so
+// a loop can start in the code of one operation and end in another
+pub struct Builder {
+    // The outermost list of nodes, followed by the body of every open loop
+    open: Vec<Vec<Node>>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder { open: vec![vec![]] }
+    }
+
+    fn push(&mut self, node: Node) {
+        self.open.last_mut().unwrap().push(node);
+    }
+
+    pub fn add(&mut self, count: i64) {
+        if count != 0 {
+            self.push(Node::Add(count));
+        }
+    }
+
+    pub fn move_by(&mut self, count: i64) {
+        if count != 0 {
+            self.push(Node::Move(count));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.push(Node::Clear);
+    }
+
+    pub fn output(&mut self) {
+        self.push(Node::Output);
+    }
+
+    pub fn input(&mut self) {
+        self.push(Node::Input);
+    }
+
+    pub fn breakpoint(&mut self) {
+        self.push(Node::Breakpoint);
+    }
+
+    pub fn open_loop(&mut self) {
+        self.open.push(vec![]);
+    }
+
+    pub fn close_loop(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.open.len() == 1 {
+            Err("unmatched ']'")?;
+        }
+        let body = self.open.pop().unwrap();
+        self.push(Node::Loop(body));
+        Ok(())
+    }
+
+    pub fn append(&mut self, nodes: Vec<Node>) {
+        self.open.last_mut().unwrap().extend(nodes);
+    }
+
+    // Adds brainf*ck source, every character that isn't an instruction is ignored
+    pub fn push_str(&mut self, source: &str) -> Result<(), Box<dyn Error>> {
+        for ch in source.chars() {
+            match ch {
+                '+' => self.add(1),
+                '-' => self.add(-1),
+                '>' => self.move_by(1),
+                '<' => self.move_by(-1),
+                '[' => self.open_loop(),
+                ']' => self.close_loop()?,
+                '.' => self.output(),
+                ',' => self.input(),
+                '#' => self.breakpoint(),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Vec<Node>, Box<dyn Error>> {
+        if self.open.len() > 1 {
+            Err("unmatched '['")?;
+        }
+        Ok(self.open.pop().unwrap())
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Node>, Box<dyn Error>> {
+    let mut builder = Builder::new();
+    builder.push_str(source)?;
+    builder.finish()
+}
+
+// Cells that have been changed since the state was last reset, None if their value is unknown.
+// Values are tracked without wrapping, so a cell is only known to be 0 whatever its width
+struct KnownCells {
+    values: HashMap<i64, Option<i64>>,
+    untouched_cells_are_zero: bool,
+    pointer: i64,
+}
+
+impl KnownCells {
+    fn unknown() -> KnownCells {
+        KnownCells {
+            values: HashMap::new(),
+            untouched_cells_are_zero: false,
+            pointer: 0,
+        }
+    }
+
+    fn current(&self) -> Option<i64> {
+        match self.values.get(&self.pointer) {
+            Some(value) => *value,
+            None if self.untouched_cells_are_zero => Some(0),
+            None => None,
+        }
+    }
+
+    // After a loop the current cell is 0 and nothing else is known
+    fn after_loop(&mut self) {
+        *self = KnownCells::unknown();
+        self.values.insert(0, Some(0));
+    }
+}
+
+// Adds a node, merging it with the previous one if both add or both move. Runs that cancel out
+// are removed
+fn push_node(nodes: &mut Vec<Node>, node: Node) {
+    match (nodes.last_mut(), &node) {
+        (Some(Node::Add(total)), Node::Add(count))
+        | (Some(Node::Move(total)), Node::Move(count)) => {
+            *total += count;
+            if *total == 0 {
+                nodes.pop();
+            }
+        }
+        _ => nodes.push(node),
+    }
+}
+
+fn optimize_nodes(nodes: Vec<Node>, known: &mut KnownCells) -> Vec<Node> {
+    let mut optimized: Vec<Node> = vec![];
+    for node in nodes {
+        let value = known.current();
+        match node {
+            Node::Add(count) => {
+                known
+                    .values
+                    .insert(known.pointer, value.map(|value| value + count));
+                push_node(&mut optimized, node);
+            }
+            Node::Move(count) => {
+                known.pointer += count;
+                push_node(&mut optimized, node);
+            }
+            // A loop that starts on a 0 never runs
+            Node::Loop(_) | Node::Clear if value == Some(0) => {}
+            // Only the current cell changes, so everything else that is known still holds
+            Node::Loop(body) if matches!(body[..], [Node::Add(1)] | [Node::Add(-1)]) => {
+                known.values.insert(known.pointer, Some(0));
+                push_node(&mut optimized, Node::Clear);
+            }
+            Node::Loop(body) => {
+                let body = optimize_nodes(body, &mut KnownCells::unknown());
+                known.after_loop();
+                push_node(&mut optimized, Node::Loop(body));
+            }
+            Node::Clear => {
+                known.values.insert(known.pointer, Some(0));
+                push_node(&mut optimized, node);
+            }
+            Node::Input => {
+                known.values.insert(known.pointer, None);
+                push_node(&mut optimized, node);
+            }
+            Node::Output | Node::Breakpoint => push_node(&mut optimized, node),
+        }
+    }
+    optimized
+}
+
+// Merges runs of adds and moves into their net effect, turns '[-]' into a clear and removes
+// loops and clears that start on a cell known to be 0. Every cell is 0 at the start of the
+// program and the current cell is 0 after a loop. Nothing is moved across a breakpoint, so it
+// sees the same state
+pub fn optimize(nodes: Vec<Node>) -> Vec<Node> {
+    let mut nodes = nodes;
+    // Removing a loop can leave more to merge or remove
+    loop {
+        let mut known = KnownCells {
+            untouched_cells_are_zero: true,
+            ..KnownCells::unknown()
+        };
+        let optimized = optimize_nodes(nodes.clone(), &mut known);
+        if optimized == nodes {
+            return optimized;
+        }
+        nodes = optimized;
+    }
+}
+
+fn write_bf(nodes: &[Node], code: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Add(count) if *count > 0 => code.push_str(&"+".repeat(*count as usize)),
+            Node::Add(count) => code.push_str(&"-".repeat(-count as usize)),
+            Node::Move(count) if *count > 0 => code.push_str(&">".repeat(*count as usize)),
+            Node::Move(count) => code.push_str(&"<".repeat(-count as usize)),
+            Node::Loop(body) => {
+                code.push('[');
+                write_bf(body, code);
+                code.push(']');
+            }
+            Node::Clear => code.push_str("[-]"),
+            Node::Output => code.push('.'),
+            Node::Input => code.push(','),
+            Node::Breakpoint => code.push('#'),
+        }
+    }
+}
+
+pub fn to_bf(nodes: &[Node]) -> String {
+    let mut code = String::new();
+    write_bf(nodes, &mut code);
+    code
+}
+
+fn write_c(nodes: &[Node], depth: usize, code: &mut String) {
+    for node in nodes {
+        let indent = "    ".repeat(depth);
+        match node {
+            Node::Add(count) => code.push_str(&format!("{}*p += {};\n", indent, count)),
+            Node::Move(count) => code.push_str(&format!("{}p += {};\n", indent, count)),
+            Node::Loop(body) => {
+                code.push_str(&format!("{}while (*p) {{\n", indent));
+                write_c(body, depth + 1, code);
+                code.push_str(&format!("{}}}\n", indent));
+            }
+            Node::Clear => code.push_str(&format!("{}*p = 0;\n", indent)),
+            Node::Output => code.push_str(&format!("{}putchar(*p);\n", indent)),
+            // The end of the input reads as 0, the same as in 'run'
+            Node::Input => code.push_str(&format!(
+                "{}c = getchar();\n{}*p = c == EOF ? 0 : c;\n",
+                indent, indent
+            )),
+            Node::Breakpoint => {}
+        }
+    }
+}
+
+// Writes the nodes as a C program with a tape of 8-bit wrapping cells
+pub fn to_c(nodes: &[Node], tape_size: usize) -> String {
+    let mut code = String::from("#include <stdio.h>\n\n");
+    code.push_str(&format!("static unsigned char tape[{}];\n\n", tape_size));
+    code.push_str("int main(void) {\n    unsigned char *p = tape;\n    int c;\n");
+    write_c(nodes, 1, &mut code);
+    code.push_str("    (void)c;\n    return 0;\n}\n");
+    code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bf;
+
+    fn optimize_str(source: &str) -> String {
+        to_bf(&optimize(parse(source).unwrap()))
+    }
+
+    #[test]
+    fn builder_matches_loops() {
+        let mut builder = Builder::new();
+        builder.push_str("+[>").unwrap();
+        builder.add(2);
+        builder.close_loop().unwrap();
+        assert_eq!(
+            builder.finish().unwrap(),
+            vec![Node::Add(1), Node::Loop(vec![Node::Move(1), Node::Add(2)])]
+        );
+        assert!(parse("[[]").is_err());
+        assert!(parse("[]]").is_err());
+    }
+
+    #[test]
+    fn optimize_merges_runs() {
+        assert_eq!(optimize_str("++-->+<<>-+-"), ">+<-");
+        assert_eq!(optimize_str("+>#<-"), "+>#<-");
+        assert_eq!(optimize_str("a+b+ comment"), "++");
+    }
+
+    #[test]
+    fn optimize_removes_dead_loops() {
+        // Every cell starts at 0
+        assert_eq!(optimize_str(">[-]<+[->+<]"), "+[->+<]");
+        // The current cell is 0 after a loop
+        assert_eq!(optimize_str(",[-][-]>+<[-]"), ",[-]>+<");
+        assert_eq!(optimize_str(",[>]<[-]>[+]"), ",[>]<[-]>");
+        // Values aren't wrapped, so this cell may not be 0
+        let source = format!("{}[-]", "+".repeat(256));
+        assert_eq!(optimize_str(&source), source);
+    }
+
+    #[test]
+    fn optimized_code_behaves_the_same() {
+        let source = ">[-]<++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.<><>[-]";
+        let optimized = optimize_str(source);
+        assert!(optimized.len() < source.len());
+
+        let config = bf::Config::default();
+        let mut output: Vec<u8> = vec![];
+        let original = bf::run(source, config.clone(), &mut "".as_bytes(), &mut vec![]).unwrap();
+        let machine = bf::run(&optimized, config, &mut "".as_bytes(), &mut output).unwrap();
+        assert_eq!(machine.tape, original.tape);
+        assert_eq!(machine.pointer, original.pointer);
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn writes_c() {
+        let code = to_c(&parse(",[.-]>").unwrap(), 100);
+        assert!(code.contains("static unsigned char tape[100];"));
+        assert!(
+            code.contains("    while (*p) {\n        putchar(*p);\n        *p += -1;\n    }\n")
+        );
+        assert!(code.contains("    p += 1;\n"));
+    }
+}
@@ -1,5 +1,16 @@
-use std::collections::{HashMap, VecDeque};
+pub mod bf;
+pub mod check;
+pub mod diagnostic;
+pub mod frontend;
+pub mod ir;
+#[cfg(test)]
+mod temp_dir;
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
+
+use diagnostic::{Diagnostic, Diagnostics};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Function {
@@ -8,8 +19,14 @@ pub enum Function {
     Plus(),
     Minus(),
     Mult(),
+    Div(),
+    Mod(),
+    DivMod(),
     NumOut(),
     CharOut(),
+    CharIn(),
+    NumIn(),
+    StringIn(),
     Write(),
     Read(),
     Mem(),
@@ -28,10 +45,106 @@ pub enum Function {
     GetStackHeight(),
     Not(),
     And(),
-    FunctionDeclaration(String),
+    FunctionDeclaration(String, Option<Signature>),
+    // Marks the function declared next as replacing an imported function of the same name
+    Override(),
+    // Marks the function declared next as only callable from its own file
+    Private(),
     FunctionCall(String),
-    StringLiteral(String),
-    Import(String),
+    StringLiteral(Vec<u8>),
+    // The file to import and the name its functions are called through, if it has one
+    Import(String, Option<String>),
+    Variable(String, usize),
+}
+
+// The declared stack effect of a function, written as '( a b -- c )' after its name
+#[derive(Debug, PartialEq, Clone)]
+pub struct Signature {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl Signature {
+    // The number of values taken and left by the function. Strings and counted lists don't have
+    // a known length, so a signature that names a 'str' or a 'list' doesn't give the number of
+    // values
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        if self.names_string() {
+            None
+        } else {
+            Some((self.inputs.len(), self.outputs.len()))
+        }
+    }
+
+    // Whether the function takes or leaves a 'str' or a 'list', a count on top of that many values
+    pub fn names_string(&self) -> bool {
+        self.inputs
+            .iter()
+            .chain(&self.outputs)
+            .any(|item| item == "str" || item == "list")
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(")?;
+        for item in &self.inputs {
+            write!(f, " {}", item)?;
+        }
+        write!(f, " --")?;
+        for item in &self.outputs {
+            write!(f, " {}", item)?;
+        }
+        write!(f, " )")
+    }
+}
+
+impl Function {
+    // The number of values an operation takes from the top of the stack and the number it
+    // leaves in their place. Returns None for control flow, declarations, function calls and
+    // 'strin', which pushes a string of unknown length
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        match self {
+            Function::Push(_) => Some((0, 1)),
+            Function::Pop() => Some((1, 0)),
+            Function::Plus() => Some((2, 1)),
+            Function::Minus() => Some((2, 1)),
+            Function::Mult() => Some((2, 1)),
+            Function::Div() => Some((2, 1)),
+            Function::Mod() => Some((2, 1)),
+            Function::DivMod() => Some((2, 2)),
+            Function::NumOut() => Some((1, 0)),
+            Function::CharOut() => Some((1, 0)),
+            Function::CharIn() => Some((0, 1)),
+            Function::NumIn() => Some((0, 1)),
+            Function::Write() => Some((2, 0)),
+            Function::Read() => Some((1, 1)),
+            Function::Mem() => Some((0, 1)),
+            Function::InitMem() => Some((0, 0)),
+            Function::LessThan() => Some((2, 1)),
+            Function::GreaterThan() => Some((2, 1)),
+            Function::Equals() => Some((2, 1)),
+            Function::Swap() => Some((2, 2)),
+            Function::Pull(number) => Some((*number as usize + 1, *number as usize + 2)),
+            Function::Dup() => Some((1, 2)),
+            Function::TwoDup() => Some((2, 4)),
+            Function::GetStackHeight() => Some((0, 1)),
+            Function::Not() => Some((1, 1)),
+            Function::And() => Some((2, 1)),
+            Function::StringLiteral(string_literal) => Some((0, string_literal.len() + 1)),
+            Function::StringIn()
+            | Function::If(_)
+            | Function::End(_)
+            | Function::Else(_)
+            | Function::While(_)
+            | Function::FunctionDeclaration(_, _)
+            | Function::Override()
+            | Function::Private()
+            | Function::FunctionCall(_)
+            | Function::Import(_, _)
+            | Function::Variable(_, _) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -40,6 +153,12 @@ pub struct Stack {
     pub top: usize,
 }
 
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Stack {
     pub fn new() -> Stack {
         Stack {
@@ -60,14 +179,44 @@ impl Stack {
     }
 }
 
-pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Function>, Box<dyn Error>> {
+// Where an operation was written in the source code, and the number of characters it spans
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    pub filepath: String,
+    pub row: usize,
+    pub col: usize,
+    pub length: usize,
+}
+
+impl Location {
+    pub fn new(token: &Token) -> Location {
+        Location {
+            filepath: token.filepath.to_string(),
+            row: token.row,
+            col: token.col,
+            length: token.value.chars().count(),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.filepath, self.row, self.col)
+    }
+}
+
+pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<(Function, Location)>, Box<dyn Error>> {
     let mut parsed_tokens: Vec<Function> = vec![];
+    let mut locations: Vec<Location> = vec![];
 
-    let mut token_iter = tokens.iter();
+    let mut token_iter = tokens.iter().peekable();
 
     while let Some(token) = token_iter.next() {
-        if let Ok(number) = token.value.parse::<u8>() {
-            parsed_tokens.push(Function::Push(number));
+        if let Some(number) = parse_number(token.value) {
+            match number {
+                Ok(byte) => parsed_tokens.push(Function::Push(byte)),
+                Err(err) => Err(Diagnostic::error(err).at(Location::new(token)))?,
+            }
         } else if token.value == "pop" {
             parsed_tokens.push(Function::Pop());
         } else if token.value == "+" {
@@ -76,10 +225,22 @@ pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Function>, Box<dyn Error>>
             parsed_tokens.push(Function::Minus());
         } else if token.value == "*" {
             parsed_tokens.push(Function::Mult());
+        } else if token.value == "/" {
+            parsed_tokens.push(Function::Div());
+        } else if token.value == "%" {
+            parsed_tokens.push(Function::Mod());
+        } else if token.value == "divmod" {
+            parsed_tokens.push(Function::DivMod());
         } else if token.value == "chout" {
             parsed_tokens.push(Function::CharOut());
         } else if token.value == "numout" {
             parsed_tokens.push(Function::NumOut());
+        } else if token.value == "chin" {
+            parsed_tokens.push(Function::CharIn());
+        } else if token.value == "numin" {
+            parsed_tokens.push(Function::NumIn());
+        } else if token.value == "strin" {
+            parsed_tokens.push(Function::StringIn());
         } else if token.value == "write" {
             parsed_tokens.push(Function::Write());
         } else if token.value == "read" {
@@ -105,16 +266,14 @@ pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Function>, Box<dyn Error>>
         } else if token.value == "swap" {
             parsed_tokens.push(Function::Swap());
         } else if token.value == "pull" {
-            if let Some(token) = token_iter.next() {
-                if let Ok(number) = token.value.parse::<u8>() {
-                    parsed_tokens.push(Function::Pull(number));
-                } else {
-                    eprintln!(
-                        "{}:{}:{} expected number, received: '{}'",
-                        token.filepath, token.row, token.col, token.value
-                    ); // Need to improve better error reporting
-                    return Err("Syntax error")?;
-                }
+            let depth = expect_word(&mut token_iter, token, "a number")?;
+            match depth.value.parse::<u8>() {
+                Ok(number) => parsed_tokens.push(Function::Pull(number)),
+                Err(_) => Err(Diagnostic::error(format!(
+                    "expected a number after 'pull', found '{}'",
+                    depth.value
+                ))
+                .at(Location::new(depth)))?,
             }
         } else if token.value == "dup" {
             parsed_tokens.push(Function::Dup());
@@ -126,118 +285,763 @@ pub fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Function>, Box<dyn Error>>
             parsed_tokens.push(Function::Not());
         } else if token.value == "&&" {
             parsed_tokens.push(Function::And());
-        } else if token.value == "fn" {
-            if let Some(token) = token_iter.next() {
-                parsed_tokens.push(Function::FunctionDeclaration(token.value.to_string()));
+        } else if token.value == "override" {
+            match token_iter.peek() {
+                Some(next) if next.value == "fn" => parsed_tokens.push(Function::Override()),
+                _ => {
+                    Err(Diagnostic::error("expected 'fn' after 'override'")
+                        .at(Location::new(token)))?
+                }
             }
+        } else if token.value == "private" {
+            match token_iter.peek() {
+                Some(next) if next.value == "fn" || next.value == "var" => {
+                    parsed_tokens.push(Function::Private())
+                }
+                _ => Err(Diagnostic::error("expected 'fn' or 'var' after 'private'")
+                    .at(Location::new(token)))?,
+            }
+        } else if token.value == "fn" {
+            let name = expect_word(&mut token_iter, token, "a function name")?;
+            let signature = match token_iter.peek() {
+                Some(open) if open.value == "(" => {
+                    token_iter.next();
+                    Some(parse_signature(&mut token_iter, name)?)
+                }
+                _ => None,
+            };
+            parsed_tokens.push(Function::FunctionDeclaration(
+                name.value.to_string(),
+                signature,
+            ));
         } else if token.value == "import" {
-            if let Some(token) = token_iter.next() {
-                parsed_tokens.push(Function::Import(token.value.to_string()));
+            let filepath = expect_word(&mut token_iter, token, "a file to import")?;
+            // 'import path as name' calls the functions of the file as 'name::function'
+            let alias = match token_iter.peek() {
+                Some(next) if next.value == "as" => {
+                    let next = token_iter.next().unwrap();
+                    let alias = expect_word(&mut token_iter, next, "a name for the import")?;
+                    if alias.value.contains("::") {
+                        Err(Diagnostic::error(format!(
+                            "import names can't contain '::': '{}'",
+                            alias.value
+                        ))
+                        .at(Location::new(alias)))?;
+                    }
+                    Some(alias.value.to_string())
+                }
+                _ => None,
+            };
+            parsed_tokens.push(Function::Import(filepath.value.to_string(), alias));
+        } else if token.value == "var" {
+            let name = expect_word(&mut token_iter, token, "a variable name")?;
+            if name.value.ends_with('@') || name.value.ends_with('!') {
+                Err(Diagnostic::error(format!(
+                    "variable names can't end with '@' or '!': '{}'",
+                    name.value
+                ))
+                .at(Location::new(name)))?;
+            }
+            if BUILTIN_WORDS.contains(&name.value) {
+                Err(Diagnostic::error(format!(
+                    "'{}' is a built-in word and can't be used as a variable name",
+                    name.value
+                ))
+                .at(Location::new(name)))?;
+            }
+            // 'var name N' declares an array of N bytes
+            let size = match token_iter.peek().map(|size| size.value.parse::<usize>()) {
+                Some(Ok(size)) => {
+                    token_iter.next();
+                    size
+                }
+                _ => 1,
+            };
+            parsed_tokens.push(Function::Variable(name.value.to_string(), size));
+        } else if token.value.starts_with('"') {
+            match parse_quoted(token.value) {
+                Ok(bytes) => parsed_tokens.push(Function::StringLiteral(bytes)),
+                Err(err) => Err(Diagnostic::error(err).at(Location::new(token)))?,
+            }
+        } else if token.value.starts_with('\'') {
+            // A character literal pushes its byte
+            match parse_quoted(token.value) {
+                Ok(bytes) if bytes.len() == 1 => parsed_tokens.push(Function::Push(bytes[0])),
+                Ok(_) => Err(Diagnostic::error(format!(
+                    "character literals must hold a single byte: {}",
+                    token.value
+                ))
+                .at(Location::new(token)))?,
+                Err(err) => Err(Diagnostic::error(err).at(Location::new(token)))?,
             }
-        } else if token.value.contains("\"") {
-            let string_value = &token.value[1..(token.value.len() - 1)];
-            parsed_tokens.push(Function::StringLiteral(string_value.to_string()));
         } else {
+            // Checked against the functions of the program once imports are read, see resolve_calls
             parsed_tokens.push(Function::FunctionCall(token.value.to_string()));
+        }
+        while locations.len() < parsed_tokens.len() {
+            locations.push(Location::new(token));
+        }
+    }
+    Ok(parsed_tokens.into_iter().zip(locations).collect())
+}
 
-            /*
-            eprintln!("{}:{}:{} could not parse token: '{}'",
-                token.filepath,
-                token.row,
-                token.col,
-                token.value
-            ); // Need to improve better error reporting
-            return Err("Syntax error")?;
-            */
+// The word that must follow a keyword, such as the name after 'fn'
+fn expect_word<'a, I: Iterator<Item = &'a Token<'a>>>(
+    token_iter: &mut I,
+    keyword: &Token,
+    expected: &str,
+) -> Result<&'a Token<'a>, Box<dyn Error>> {
+    match token_iter.next() {
+        Some(token) => Ok(token),
+        None => Err(
+            Diagnostic::error(format!("expected {} after '{}'", expected, keyword.value))
+                .at(Location::new(keyword)),
+        )?,
+    }
+}
+
+// Reads the rest of a signature after its '('
+fn parse_signature<'a, I: Iterator<Item = &'a Token<'a>>>(
+    token_iter: &mut I,
+    name: &Token,
+) -> Result<Signature, Box<dyn Error>> {
+    let mut inputs: Vec<String> = vec![];
+    let mut outputs: Vec<String> = vec![];
+    let mut reading_outputs = false;
+
+    for token in token_iter {
+        if token.value == ")" {
+            if !reading_outputs {
+                break;
+            }
+            return Ok(Signature { inputs, outputs });
+        } else if token.value == "--" {
+            if reading_outputs {
+                break;
+            }
+            reading_outputs = true;
+        } else if reading_outputs {
+            outputs.push(token.value.to_string());
+        } else {
+            inputs.push(token.value.to_string());
         }
     }
-    Ok(parsed_tokens)
+    Err(Diagnostic::error(format!(
+        "expected a signature such as '( a b -- c )' after 'fn {}'",
+        name.value
+    ))
+    .at(Location::new(name)))?
 }
 
+#[derive(Debug, Clone)]
 pub struct Program {
-    pub imports: VecDeque<String>,
+    // Files still to be imported, with the name each is imported as and where it was imported
+    pub imports: VecDeque<(String, Option<String>, Location)>,
     pub functions: HashMap<String, Vec<Function>>,
+    // Names, sizes and declarations of the variables, in declaration order
+    pub variables: Vec<(String, usize, Location)>,
+    // The location of every operation in each function
+    pub locations: HashMap<String, Vec<Location>>,
+    // The location of the 'fn' that declares each function
+    pub declarations: HashMap<String, Location>,
+    pub signatures: HashMap<String, Signature>,
+    // Functions declared with 'override fn'
+    pub overrides: HashSet<String>,
+    // Functions and variables declared with 'private fn' or 'private var'
+    pub private: HashSet<String>,
+    // The name each function or variable that was renamed when imports were merged is written
+    // as, see display_name
+    pub names: HashMap<String, String>,
 }
 
 impl Program {
-    pub fn new(imports: VecDeque<String>, functions: HashMap<String, Vec<Function>>) -> Program {
-        Program { imports, functions }
+    pub fn new(
+        imports: VecDeque<(String, Option<String>, Location)>,
+        functions: HashMap<String, Vec<Function>>,
+        variables: Vec<(String, usize, Location)>,
+        locations: HashMap<String, Vec<Location>>,
+        declarations: HashMap<String, Location>,
+        signatures: HashMap<String, Signature>,
+    ) -> Program {
+        Program {
+            imports,
+            functions,
+            variables,
+            locations,
+            declarations,
+            signatures,
+            overrides: HashSet::new(),
+            private: HashSet::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    // The name a function or variable is written as in the source, which is what errors refer to
+    // it by
+    pub fn display_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.names.get(name).map_or(name, String::as_str)
     }
 
-    pub fn consume(&mut self, mut program: Program) {
+    // Adds an imported program to this one. A function can only be defined once, unless one of
+    // its definitions is declared with 'override fn', which then replaces the other everywhere,
+    // including in calls from the file that defines the other
+    pub fn consume(&mut self, mut program: Program) -> Result<(), Box<dyn Error>> {
         // add all imports to self
-        while let Some(q_item) = &program.imports.pop_front() {
-            self.imports.push_back(q_item.to_string());
+        while let Some(import) = program.imports.pop_front() {
+            self.imports.push_back(import);
+        }
+
+        // Sorted so the same error is reported first every time
+        let mut names: Vec<String> = program.functions.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            if self.functions.contains_key(&name) {
+                let overridden = self.overrides.contains(&name);
+                if overridden == program.overrides.contains(&name) {
+                    Err(duplicate_function(
+                        &name,
+                        self.declarations.get(&name),
+                        program.declarations.get(&name),
+                    )
+                    .with_note("declare one of them with 'override fn' to replace the other"))?;
+                }
+                if overridden {
+                    continue;
+                }
+                self.overrides.insert(name.clone());
+            }
+            self.functions
+                .insert(name.clone(), program.functions.remove(&name).unwrap());
+            // The replaced function's details go with it
+            match program.locations.remove(&name) {
+                Some(locations) => self.locations.insert(name.clone(), locations),
+                None => self.locations.remove(&name),
+            };
+            match program.declarations.remove(&name) {
+                Some(declaration) => self.declarations.insert(name.clone(), declaration),
+                None => self.declarations.remove(&name),
+            };
+            match program.signatures.remove(&name) {
+                Some(signature) => self.signatures.insert(name.clone(), signature),
+                None => self.signatures.remove(&name),
+            };
+        }
+
+        self.variables.append(&mut program.variables);
+        self.names.extend(program.names);
+        Ok(())
+    }
+}
+
+// A function defined in two places, first is where it was defined first if that is known
+fn duplicate_function(
+    name: &str,
+    first: Option<&Location>,
+    second: Option<&Location>,
+) -> Diagnostic {
+    let error = Diagnostic::error(format!("function '{}' is defined more than once", name))
+        .at_some(second.cloned());
+    match first {
+        Some(first) => error.with_secondary(first.clone(), "first defined here"),
+        None => error,
+    }
+}
+
+// Size of the memory placed by 'initmem'
+pub const MEMORY_SIZE: usize = 256;
+
+// Gives every variable an address in memory and replaces its uses in the function bodies:
+// 'name' pushes the address, 'name@' reads the variable and 'name!' writes the value on top
+// of the stack to it. Variables are allocated from the end of memory down so they stay clear
+// of programs that use addresses counted up from 'mem'
+pub fn resolve_variables(program: &mut Program) -> Result<(), Box<dyn Error>> {
+    let mut addresses: HashMap<String, u8> = HashMap::new();
+    let mut free = MEMORY_SIZE;
+
+    let mut declarations: HashMap<&str, &Location> = HashMap::new();
+
+    for (name, size, location) in &program.variables {
+        let display = program.display_name(name);
+        if let Some(&first) = declarations.get(name.as_str()) {
+            Err(
+                Diagnostic::error(format!("variable '{}' is declared more than once", display))
+                    .at(location.clone())
+                    .with_secondary(first.clone(), "first declared here"),
+            )?;
+        }
+        if program.functions.contains_key(name) {
+            let message = format!("variable '{}' has the same name as a function", display);
+            let error = Diagnostic::error(message).at(location.clone());
+            Err(match program.declarations.get(name) {
+                Some(function) => error.with_secondary(function.clone(), "function declared here"),
+                None => error,
+            })?;
+        }
+        if *size == 0 {
+            let message = format!("variable '{}' must have a size of at least 1", display);
+            Err(Diagnostic::error(message).at(location.clone()))?;
+        }
+        if *size > free {
+            Err(Diagnostic::error(format!(
+                "not enough memory for variable '{}': needs {} bytes, {} left",
+                display, size, free
+            ))
+            .at(location.clone())
+            .with_note(format!("variables share {} bytes of memory", MEMORY_SIZE)))?;
+        }
+        declarations.insert(name, location);
+        free -= size;
+        addresses.insert(name.to_string(), free as u8);
+    }
+
+    for (function_name, function) in program.functions.iter_mut() {
+        let mut resolved: Vec<Function> = Vec::with_capacity(function.len());
+        // How many operations each original operation was replaced with
+        let mut counts: Vec<usize> = Vec::with_capacity(function.len());
+        for operation in function.drain(..) {
+            let length = resolved.len();
+            let Function::FunctionCall(name) = &operation else {
+                resolved.push(operation);
+                counts.push(1);
+                continue;
+            };
+            if let Some(&address) = addresses.get(name) {
+                resolved.push(Function::Push(address));
+            } else if let Some(&address) = name.strip_suffix('@').and_then(|n| addresses.get(n)) {
+                resolved.push(Function::Push(address));
+                resolved.push(Function::Read());
+            } else if let Some(&address) = name.strip_suffix('!').and_then(|n| addresses.get(n)) {
+                resolved.push(Function::Push(address));
+                resolved.push(Function::Swap());
+                resolved.push(Function::Write());
+            } else {
+                resolved.push(operation);
+            }
+            counts.push(resolved.len() - length);
+        }
+        *function = resolved;
+
+        if let Some(locations) = program.locations.get_mut(function_name) {
+            *locations = locations
+                .drain(..)
+                .zip(counts)
+                .flat_map(|(location, count)| std::iter::repeat_n(location, count))
+                .collect();
+        }
+    }
+    Ok(())
+}
+
+// Folds arithmetic and comparisons on values known at compile time into a single push, so
+// 'mem 13 +' pushes 13 instead of adding at runtime. Results wrap like bytes on the stack do.
+// Only operations next to each other are folded, never across the start or end of a block
+pub fn fold_constants(program: &mut Program) -> Result<(), Box<dyn Error>> {
+    for (function_name, function) in program.functions.iter_mut() {
+        // Each operation alongside the index of the first operation it was folded from
+        let mut folded: Vec<(Function, usize)> = Vec::with_capacity(function.len());
+        for (i, operation) in function.drain(..).enumerate() {
+            let operation = match operation {
+                Function::Mem() => Function::Push(0),
+                operation => operation,
+            };
+            let length = folded.len();
+            let constants = match folded.as_slice() {
+                [.., (Function::Push(a), _), (Function::Push(b), _)] => (Some(*a), Some(*b)),
+                [.., (Function::Push(b), _)] => (None, Some(*b)),
+                _ => (None, None),
+            };
+            let result = match (&operation, constants) {
+                (Function::Plus(), (Some(a), Some(b))) => Some((2, a.wrapping_add(b))),
+                (Function::Minus(), (Some(a), Some(b))) => Some((2, a.wrapping_sub(b))),
+                (Function::Mult(), (Some(a), Some(b))) => Some((2, a.wrapping_mul(b))),
+                (Function::Equals(), (Some(a), Some(b))) => Some((2, (a == b) as u8)),
+                (Function::LessThan(), (Some(a), Some(b))) => Some((2, (a < b) as u8)),
+                (Function::GreaterThan(), (Some(a), Some(b))) => Some((2, (a > b) as u8)),
+                (Function::Not(), (_, Some(b))) => Some((1, 1u8.wrapping_sub(b))),
+                _ => None,
+            };
+            match result {
+                Some((inputs, byte)) => {
+                    let (_, first) = folded[length - inputs];
+                    folded.truncate(length - inputs);
+                    folded.push((Function::Push(byte), first));
+                }
+                None => folded.push((operation, i)),
+            }
+        }
+
+        if let Some(locations) = program.locations.get_mut(function_name) {
+            *locations = folded
+                .iter()
+                .filter_map(|(_, i)| locations.get(*i).cloned())
+                .collect();
+        }
+        *function = folded.into_iter().map(|(operation, _)| operation).collect();
+        // The blocks were linked before folding moved them
+        let locations = program
+            .locations
+            .get(function_name)
+            .map_or(&[][..], Vec::as_slice);
+        create_references_for_blocks(function, locations)?;
+    }
+    Ok(())
+}
+
+// Reads a numeric literal: decimal, hex (0x41), binary (0b1010) or negative (-1 is 255, the same
+// byte as 0 1 -). None if the token isn't a number, an error if it doesn't fit in a byte
+fn parse_number(value: &str) -> Option<Result<u8, String>> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let (radix, digits) = if let Some(digits) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (16, digits)
+    } else if let Some(digits) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (2, digits)
+    } else {
+        (10, digits)
+    };
+    if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+        return None;
+    }
+
+    let number = u64::from_str_radix(digits, radix).unwrap_or(u64::MAX);
+    Some(match (negative, number) {
+        (false, 0..=255) => Ok(number as u8),
+        (true, 0..=128) => Ok((number as u8).wrapping_neg()),
+        _ => Err(format!(
+            "number '{}' is out of range, numbers must be from -128 to 255",
+            value
+        )),
+    })
+}
+
+// Reads the bytes of a string or character literal, which ends with the quote it starts with.
+// The escape sequences \n, \t, \\, \", \', \0 and \xNN (two hex digits) are replaced
+fn parse_quoted(literal: &str) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = vec![];
+    let mut chars = literal.chars();
+    let quote = chars.next();
+    while let Some(ch) = chars.next() {
+        match ch {
+            _ if Some(ch) == quote && chars.as_str().is_empty() => return Ok(bytes),
+            '\\' => {
+                let byte = match chars.next() {
+                    Some('n') => b'\n',
+                    Some('t') => b'\t',
+                    Some('\\') => b'\\',
+                    Some('"') => b'"',
+                    Some('\'') => b'\'',
+                    Some('0') => 0,
+                    Some('x') => {
+                        let digits: String = chars.by_ref().take(2).collect();
+                        match u8::from_str_radix(&digits, 16) {
+                            Ok(byte) if digits.len() == 2 => byte,
+                            _ => Err(format!(
+                                "'\\x' must be followed by two hex digits, found '{}'",
+                                digits
+                            ))?,
+                        }
+                    }
+                    Some(ch) => Err(format!("unknown escape sequence '\\{}'", ch))?,
+                    None => break,
+                };
+                bytes.push(byte);
+            }
+            _ => {
+                let mut buffer = [0; 4];
+                bytes.extend(ch.encode_utf8(&mut buffer).as_bytes());
+            }
         }
+    }
+    match quote {
+        Some('\'') => Err(format!("unterminated character literal: {}", literal)),
+        _ => Err(format!("unterminated string literal: {}", literal)),
+    }
+}
+
+// The words parse_tokens reads as operations or keywords
+const BUILTIN_WORDS: [&str; 35] = [
+    "pop", "+", "-", "*", "/", "%", "divmod", "chout", "numout", "chin", "numin", "strin", "write",
+    "read", "mem", "initmem", "if", "end", "else", "while", "<", ">", "=", "swap", "pull", "dup",
+    "2dup", "?", "not", "&&", "fn", "override", "private", "import", "var",
+];
+
+// The number of single character insertions, deletions, substitutions and swaps of neighbouring
+// characters that turn one word into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between the first i characters of a and first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + (a[i - 1] != b[j - 1]) as usize;
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+// The known word closest to word, if it is close enough for word to be a typo of it
+pub(crate) fn closest_word<'a>(word: &str, known: &[&'a str]) -> Option<&'a str> {
+    let (distance, closest) = known
+        .iter()
+        .map(|known| (edit_distance(word, known), *known))
+        .min()?;
+    (distance <= (word.chars().count() / 3).max(1)).then_some(closest)
+}
 
-        // Add all functions of program to self (non overriding)
-        // TODO: Make this non overriding
-        for (k, v) in program.functions {
-            self.functions.insert(k, v);
+// Checks that every word left as a function call names a function of the program, after imports
+// have been merged and variables resolved. Every unknown word is reported with where it was
+// written and the closest known word, if there is one close enough to be a typo
+pub fn resolve_calls(program: &Program) -> Result<(), Box<dyn Error>> {
+    let mut known: Vec<&str> = BUILTIN_WORDS.to_vec();
+    // Functions of a file imported with a name, or private to a file, can't be called by the
+    // name they are known by in the whole program
+    known.extend(
+        program
+            .functions
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !name.contains("::")),
+    );
+    let variables: Vec<String> = program
+        .variables
+        .iter()
+        .filter(|(name, _, _)| !name.contains("::"))
+        .flat_map(|(name, _, _)| [name.clone(), format!("{}@", name), format!("{}!", name)])
+        .collect();
+    known.extend(variables.iter().map(String::as_str));
+
+    let mut unknown: Vec<(Option<&Location>, &str)> = vec![];
+    for (function_name, function) in &program.functions {
+        for (i, operation) in function.iter().enumerate() {
+            if let Function::FunctionCall(name) = operation
+                && !program.functions.contains_key(name)
+            {
+                let location = program
+                    .locations
+                    .get(function_name)
+                    .and_then(|locations| locations.get(i));
+                unknown.push((location, name));
+            }
         }
     }
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    // Reported in the order they were written
+    unknown.sort_by_key(|(location, _)| {
+        location.map(|location| (location.filepath.clone(), location.row, location.col))
+    });
+    let errors: Vec<Diagnostic> = unknown
+        .iter()
+        .map(|(location, name)| {
+            let error =
+                Diagnostic::error(format!("unknown word '{}'", name)).at_some(location.cloned());
+            match closest_word(name, &known) {
+                Some(word) => error.with_note(format!("did you mean '{}'?", word)),
+                None => error,
+            }
+        })
+        .collect();
+    Err(Diagnostics(errors))?
 }
 
-pub fn parse_program_structure(parsed_tokens: Vec<Function>) -> Result<Program, Box<dyn Error>> {
+pub fn parse_program_structure(
+    parsed_tokens: Vec<(Function, Location)>,
+) -> Result<Program, Box<dyn Error>> {
     let mut functions: HashMap<String, Vec<Function>> = HashMap::new();
-    let mut q: VecDeque<String> = VecDeque::new();
+    let mut locations: HashMap<String, Vec<Location>> = HashMap::new();
+    let mut declarations: HashMap<String, Location> = HashMap::new();
+    let mut signatures: HashMap<String, Signature> = HashMap::new();
+    let mut overrides: HashSet<String> = HashSet::new();
+    let mut private: HashSet<String> = HashSet::new();
+    let mut q: VecDeque<(String, Option<String>, Location)> = VecDeque::new();
+    let mut variables: Vec<(String, usize, Location)> = Vec::new();
 
     let mut block_tokens: Vec<(usize, u8)> = Vec::new();
     let mut function_tokens: Vec<Function> = Vec::new();
+    let mut function_locations: Vec<Location> = Vec::new();
 
     let mut function_name: String = String::new();
+    // Whether the next function is declared with 'override fn', or the next function or
+    // variable with 'private'
+    let mut overriding = false;
+    let mut privately = false;
 
     const IF: u8 = 0;
     const ELSE: u8 = 1;
     const WHILE: u8 = 2;
     const FUNCDEF: u8 = 3;
 
-    for i in 0..parsed_tokens.len() {
-        match &parsed_tokens[i] {
+    // Where the block that is open at the given position of block_tokens was started
+    let opened_at = |block: &(usize, u8)| {
+        let word = match block.1 {
+            IF => "if",
+            ELSE => "else",
+            WHILE => "while",
+            _ => "fn",
+        };
+        (parsed_tokens[block.0].1.clone(), word)
+    };
+
+    for (i, (parsed_token, location)) in parsed_tokens.iter().enumerate() {
+        match parsed_token {
             Function::If(_reference) => {
                 block_tokens.push((i, IF));
-                function_tokens.push(parsed_tokens[i].clone());
+                function_tokens.push(parsed_token.clone());
+                function_locations.push(location.clone());
             }
             Function::Else(_reference) => {
+                match block_tokens.last() {
+                    Some((_, IF)) => {}
+                    Some(block) => {
+                        let (opened, word) = opened_at(block);
+                        Err(Diagnostic::error("'else' without a matching 'if'")
+                            .at(location.clone())
+                            .with_secondary(
+                                opened,
+                                format!("the innermost open block is this '{}'", word),
+                            ))?;
+                    }
+                    None => {
+                        Err(Diagnostic::error("'else' without a matching 'if'")
+                            .at(location.clone()))?
+                    }
+                }
                 block_tokens.push((i, ELSE));
-                function_tokens.push(parsed_tokens[i].clone());
+                function_tokens.push(parsed_token.clone());
+                function_locations.push(location.clone());
             }
             Function::While(_reference) => {
                 block_tokens.push((i, WHILE));
-                function_tokens.push(parsed_tokens[i].clone());
+                function_tokens.push(parsed_token.clone());
+                function_locations.push(location.clone());
             }
-            Function::FunctionDeclaration(fname) => {
+            Function::FunctionDeclaration(fname, signature) => {
+                if let Some(block) = block_tokens.first() {
+                    let (opened, _) = opened_at(block);
+                    Err(Diagnostic::error(format!(
+                        "'fn {}' is declared inside '{}'",
+                        fname, function_name
+                    ))
+                    .at(location.clone())
+                    .with_secondary(opened, format!("'{}' is still open", function_name))
+                    .with_note("each 'fn' must be closed with 'end' before the next one"))?;
+                }
+                if declarations.contains_key(fname) {
+                    Err(duplicate_function(
+                        fname,
+                        declarations.get(fname),
+                        Some(location),
+                    ))?;
+                }
+                if overriding {
+                    overrides.insert(fname.to_string());
+                    overriding = false;
+                }
+                if privately {
+                    private.insert(fname.to_string());
+                    privately = false;
+                }
                 function_name = fname.to_string();
+                declarations.insert(fname.to_string(), location.clone());
+                if let Some(signature) = signature {
+                    signatures.insert(fname.to_string(), signature.clone());
+                }
                 block_tokens.push((i, FUNCDEF));
             }
             Function::End(_reference) => {
-                let (_index, block_word_type) = block_tokens[block_tokens.len() - 1];
+                let Some(&(_index, block_word_type)) = block_tokens.last() else {
+                    Err(Diagnostic::error("'end' without an open block").at(location.clone()))?
+                };
                 if block_word_type == FUNCDEF {
+                    let _tk = block_tokens.pop();
+                    locations.insert(function_name.clone(), function_locations);
                     functions.insert(function_name, function_tokens);
                     function_tokens = Vec::new();
+                    function_locations = Vec::new();
                     function_name = String::new();
                 } else if block_word_type == ELSE {
                     let _tk = block_tokens.pop();
                     let _tk = block_tokens.pop();
-                    function_tokens.push(parsed_tokens[i].clone());
+                    function_tokens.push(parsed_token.clone());
+                    function_locations.push(location.clone());
                 } else {
                     let _tk = block_tokens.pop();
-                    function_tokens.push(parsed_tokens[i].clone());
+                    function_tokens.push(parsed_token.clone());
+                    function_locations.push(location.clone());
                 }
             }
-            Function::Import(filename) => {
-                q.push_back(filename.clone());
+            Function::Override() => {
+                overriding = true;
+            }
+            Function::Private() => {
+                privately = true;
+            }
+            Function::Import(filename, alias) => {
+                q.push_back((filename.clone(), alias.clone(), location.clone()));
+            }
+            Function::Variable(name, size) => {
+                if privately {
+                    private.insert(name.clone());
+                    privately = false;
+                }
+                variables.push((name.clone(), *size, location.clone()));
             }
             _ => {
-                function_tokens.push(parsed_tokens[i].clone());
+                function_tokens.push(parsed_token.clone());
+                function_locations.push(location.clone());
             }
         }
     }
-    Ok(Program::new(q, functions))
+    if let Some(block) = block_tokens.last() {
+        let (opened, word) = opened_at(block);
+        Err(Diagnostic::error(format!("'{}' is never closed", word))
+            .at(opened)
+            .with_note("every 'if', 'while' and 'fn' needs a matching 'end'"))?;
+    }
+    Ok(Program {
+        imports: q,
+        functions,
+        variables,
+        locations,
+        declarations,
+        signatures,
+        overrides,
+        private,
+        names: HashMap::new(),
+    })
 }
 
-pub fn create_references_for_blocks(parsed_tokens: &mut Vec<Function>) {
+// Links every block word to the index it jumps to. locations holds where each operation was
+// written, for reporting a block word that doesn't close anything
+pub fn create_references_for_blocks(
+    parsed_tokens: &mut [Function],
+    locations: &[Location],
+) -> Result<(), Box<dyn Error>> {
     let mut block_tokens: Vec<(usize, u8)> = vec![];
+    let unmatched =
+        |i: usize, message: &str| Diagnostic::error(message).at_some(locations.get(i).cloned());
 
     const IF: u8 = 0;
     const ELSE: u8 = 1;
@@ -249,14 +1053,16 @@ pub fn create_references_for_blocks(parsed_tokens: &mut Vec<Function>) {
                 block_tokens.push((i, IF));
             }
             Function::Else(_reference) => {
-                let (index, block_word_type) = block_tokens[block_tokens.len() - 1];
-                if block_word_type == IF {
-                    parsed_tokens[index] = Function::If(Some(i + 1));
-                    block_tokens.push((i, ELSE));
-                }
+                let Some(&(index, IF)) = block_tokens.last() else {
+                    Err(unmatched(i, "'else' without a matching 'if'"))?
+                };
+                parsed_tokens[index] = Function::If(Some(i + 1));
+                block_tokens.push((i, ELSE));
             }
             Function::End(_reference) => {
-                let (index, block_word_type) = block_tokens[block_tokens.len() - 1];
+                let Some(&(index, block_word_type)) = block_tokens.last() else {
+                    Err(unmatched(i, "'end' without an open block"))?
+                };
                 if block_word_type == IF {
                     parsed_tokens[index] = Function::If(Some(i));
                     parsed_tokens[i] = Function::End(Some(i + 1));
@@ -278,6 +1084,10 @@ pub fn create_references_for_blocks(parsed_tokens: &mut Vec<Function>) {
             _ => {}
         }
     }
+    if let Some(&(index, _)) = block_tokens.last() {
+        Err(unmatched(index, "block is never closed with 'end'"))?;
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -304,25 +1114,30 @@ pub fn tokenize_line<'a>(filepath: &'a str, line_number: usize, source: &'a str)
 
     let mut token_start = 0;
     let mut moving_start = true;
-    let mut reading_string = false;
+    // The quote that started the string or character literal being read
+    let mut quote: Option<char> = None;
+    // Whether the last character in a literal was a backslash, escaping this one
+    let mut escaped = false;
 
     for (col, ch) in source.char_indices() {
         if moving_start {
-            if col != source.len() - 1 {
-                if &source[col..(col + 2)] == "//" {
-                    return tokens;
-                }
+            if source[col..].starts_with("//") {
+                return tokens;
             }
             if !ch.is_whitespace() {
                 token_start = col;
                 moving_start = false;
-                if ch == '"' {
-                    reading_string = true;
+                if ch == '"' || ch == '\'' {
+                    quote = Some(ch);
                 }
             }
         } else {
-            if reading_string {
-                if ch == '"' {
+            if quote.is_some() {
+                if escaped {
+                    escaped = false;
+                } else if ch == '\\' {
+                    escaped = true;
+                } else if Some(ch) == quote {
                     let token = Token::new(
                         filepath,
                         line_number,
@@ -331,7 +1146,7 @@ pub fn tokenize_line<'a>(filepath: &'a str, line_number: usize, source: &'a str)
                     );
                     tokens.push(token);
                     moving_start = true;
-                    reading_string = false;
+                    quote = None;
                 }
             } else if ch.is_whitespace() {
                 let token = Token::new(
@@ -344,12 +1159,13 @@ pub fn tokenize_line<'a>(filepath: &'a str, line_number: usize, source: &'a str)
                 moving_start = true;
             }
         }
-        if col == source.len() - 1 && !ch.is_whitespace() && ch != '"' {
+        // A token still open at the end of the line ends there, including an unterminated literal
+        if col + ch.len_utf8() == source.len() && !moving_start {
             let token = Token::new(
                 filepath,
                 line_number,
                 token_start + 1,
-                &source[token_start..(col + 1)],
+                &source[token_start..],
             );
             tokens.push(token);
         }
@@ -357,16 +1173,14 @@ pub fn tokenize_line<'a>(filepath: &'a str, line_number: usize, source: &'a str)
     tokens
 }
 
-pub fn tokenize_source_code<'a>(filepath: &'a str, source: &'a String) -> Vec<Token<'a>> {
+pub fn tokenize_source_code<'a>(filepath: &'a str, source: &'a str) -> Vec<Token<'a>> {
     let mut tokens: Vec<Token> = vec![];
 
-    let mut line_number = 1;
-    for line in source.lines() {
+    for (line_number, line) in (1..).zip(source.lines()) {
         let temp_tokens = tokenize_line(filepath, line_number, line);
         for token in temp_tokens {
             tokens.push(token);
         }
-        line_number += 1;
     }
     tokens
 }
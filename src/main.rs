@@ -1,310 +1,1697 @@
-use phronima::{Function, Program, Stack};
+use phronima::bf;
+use phronima::check::{self, StackEffect};
+use phronima::diagnostic::{self, Diagnostic};
+use phronima::frontend;
+use phronima::ir;
+use phronima::{Function, Location, Program, Stack};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::process;
 
+#[cfg(test)]
+mod temp_dir;
+
+// Each address in memory takes up 4 brainf*ck cells, the data being stored in the last
+const MEMORY_CELL_SIZE: usize = 4;
+// Cell holding the marker placed by 'initmem', the first address starts after it
+const MEMORY_START: usize = bf::TAPE_SIZE - 256 * MEMORY_CELL_SIZE - 1;
+
+// The language the compiled code is written out in
+enum Target {
+    Bf,
+    C,
+}
+
+struct CompileOptions {
+    // Emits a '#' after the code of every operation, see is_traced
+    trace: bool,
+    // Folds constants, pushes bytes with the shortest code and passes the compiled code through
+    // ir::optimize
+    optimize: bool,
+    // The width of the cells the code is run on, the shortest code for a byte needs 8-bit cells
+    cell_width: bf::CellWidth,
+    target: Target,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            trace: false,
+            optimize: true,
+            cell_width: bf::CellWidth::U8,
+            target: Target::Bf,
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if &args[1] == "sim" || &args[1] == "com" {
         check_args(args.len());
         let filepath = &args[2];
-        let program: Program = read_program_from_file(filepath).unwrap_or_else(|err| {
-            eprintln!("Application error: {err}");
-            process::exit(1);
-        });
-        if !program.functions.contains_key("main") {
-            eprintln!("Could not find function main");
-            process::exit(1);
-        }
+        let (search_path, options) = read_search_path(&args[3..]).unwrap_or_else(report_error);
+        let program: Program =
+            frontend::read_program(filepath, &search_path).unwrap_or_else(report_error);
         if &args[1] == "sim" {
-            simulate_program(program);
+            if let Some(option) = options.first() {
+                report_error::<()>(format!("Unknown option: {}", option).into());
+            }
+            let _ = simulate_program(
+                program,
+                &mut io::stdin().lock(),
+                &mut io::stdout().lock(),
+                &mut |_, _, _, _| {},
+            )
+            .unwrap_or_else(report_error);
         } else if &args[1] == "com" {
             check_args(args.len());
-            let compiled_code = compile_program(program).unwrap_or_else(|err| {
-                eprintln!("Application error: {err}");
-                process::exit(1);
-            });
-            let new_filepath = change_extension_to_bf(filepath);
+            let options = read_compile_options(&options).unwrap_or_else(report_error);
+            let compiled_code = compile_program(program, &options).unwrap_or_else(report_error);
+            let new_filepath = match options.target {
+                Target::Bf => change_extension(filepath, "bf"),
+                Target::C => change_extension(filepath, "c"),
+            };
             let _ = write_program_to_file(&new_filepath, compiled_code);
         }
+    } else if &args[1] == "run" {
+        check_args(args.len());
+        let (search_path, options) = read_search_path(&args[3..]).unwrap_or_else(report_error);
+        let (config, options) = read_run_config(&options).unwrap_or_else(report_error);
+        run_program_from_file(&args[2], &search_path, config, &options)
+            .unwrap_or_else(report_error);
     } else if &args[1] == "rec" {
         println!("Creating compilatin test validation files...\n");
         let _ = record_for_test();
         println!("\ncomplete.");
     } else {
-        eprintln!("Must state whether to compile 'com' or simulate 'sim' the program");
+        eprintln!("Must state whether to compile 'com', simulate 'sim' or run 'run' the program");
         process::exit(1);
     }
 }
 
+// Prints the error, with the lines of source it points at, and exits
+fn report_error<T>(err: Box<dyn Error>) -> T {
+    let read_source = |path: &str| fs::read_to_string(path).ok();
+    eprint!("{}", diagnostic::render_error(&*err, &read_source));
+    process::exit(1);
+}
+
+// Prints the warning, with the lines of source it points at, and carries on
+fn report_warning(warning: &Diagnostic) {
+    let read_source = |path: &str| fs::read_to_string(path).ok();
+    eprint!("{}", warning.render(&read_source));
+}
+
 fn check_args(num_args: usize) {
     if num_args < 3 {
-        eprintln!("Must provide 2 arguments\n['sim', 'com'] and 'filepath'");
+        eprintln!("Must provide 2 arguments\n['sim', 'com', 'run'] and 'filepath'");
         process::exit(1);
     }
 }
 
-fn read_program_from_file(filepath: &str) -> Result<Program, Box<dyn Error>> {
-    let source = fs::read_to_string(filepath)?;
-    let tokens = phronima::tokenize_source_code(filepath, &source);
-    let parsed_tokens = phronima::parse_tokens(tokens)?;
-    let mut program = phronima::parse_program_structure(parsed_tokens)?;
-    let _ = handle_imports(&mut program);
-    for (_fname, fblock) in &mut program.functions {
-        phronima::create_references_for_blocks(fblock);
+// Takes the '-I dir' options out of options, returning the directories imports are searched for
+// in and the options that are left. The directories of PHRONIMA_PATH are searched after them
+fn read_search_path(options: &[String]) -> Result<(Vec<PathBuf>, Vec<String>), Box<dyn Error>> {
+    let mut search_path: Vec<PathBuf> = vec![];
+    let mut rest: Vec<String> = vec![];
+    let mut option_iter = options.iter();
+    while let Some(option) = option_iter.next() {
+        if option == "-I" {
+            let directory = option_iter.next().ok_or("-I expects a directory")?;
+            search_path.push(PathBuf::from(directory));
+        } else if let Some(directory) = option.strip_prefix("-I") {
+            search_path.push(PathBuf::from(directory));
+        } else {
+            rest.push(option.to_string());
+        }
     }
-    Ok(program)
+    if let Some(paths) = env::var_os("PHRONIMA_PATH") {
+        search_path.extend(env::split_paths(&paths));
+    }
+    Ok((search_path, rest))
+}
+
+// The programs in tests/ are recorded without optimizing, so the code generated for each
+// operation isn't folded away or merged with its neighbours
+fn compile_program_from_file(filepath: &str) -> Result<String, Box<dyn Error>> {
+    let program = frontend::read_program(filepath, &[])?;
+    let options = CompileOptions {
+        optimize: false,
+        ..CompileOptions::default()
+    };
+    compile_program(program, &options)
 }
 
-fn handle_imports(program: &mut Program) -> Result<(), Box<dyn Error>> {
-    while let Some(filepath) = program.imports.pop_front() {
-        let source = fs::read_to_string(&filepath)?;
-        let tokens = phronima::tokenize_source_code(&filepath, &source);
-        let parsed_tokens = phronima::parse_tokens(tokens)?;
-        let import_program = phronima::parse_program_structure(parsed_tokens)?;
-        program.consume(import_program);
+// Options for 'com': --no-optimize and --emit [bf, c]
+fn read_compile_options(options: &[String]) -> Result<CompileOptions, Box<dyn Error>> {
+    let mut compile_options = CompileOptions::default();
+    let mut option_iter = options.iter();
+    while let Some(option) = option_iter.next() {
+        if option == "--no-optimize" {
+            compile_options.optimize = false;
+        } else if option == "--emit" {
+            compile_options.target = match option_iter.next().map(String::as_str) {
+                Some("bf") => Target::Bf,
+                Some("c") => Target::C,
+                _ => Err("--emit expects bf or c")?,
+            };
+        } else {
+            Err(format!("Unknown option: {}", option))?;
+        }
     }
-    Ok(())
+    Ok(compile_options)
 }
 
-fn compile_program_from_file(filepath: &str) -> Result<String, Box<dyn Error>> {
-    let source = fs::read_to_string(filepath)?;
-    let bf_code = compile_program_from_source(filepath, source)?;
-    Ok(bf_code)
+// Options for 'run': --cell-width [8, 16, 32], --no-wrap and --no-optimize
+fn read_run_config(options: &[String]) -> Result<(bf::Config, CompileOptions), Box<dyn Error>> {
+    let mut config = bf::Config::default();
+    let mut compile_options = CompileOptions::default();
+    let mut option_iter = options.iter();
+    while let Some(option) = option_iter.next() {
+        if option == "--cell-width" {
+            let bits = option_iter
+                .next()
+                .ok_or("--cell-width expects 8, 16 or 32")?;
+            config.cell_width =
+                bf::CellWidth::from_bits(bits).ok_or("--cell-width expects 8, 16 or 32")?;
+        } else if option == "--no-wrap" {
+            config.wrapping = false;
+        } else if option == "--no-optimize" {
+            compile_options.optimize = false;
+        } else {
+            Err(format!("Unknown option: {}", option))?;
+        }
+    }
+    compile_options.cell_width = config.cell_width;
+    Ok((config, compile_options))
 }
 
-fn compile_program_from_source(
+// Runs a brainf*ck file, or compiles and runs a phronima file
+fn run_program_from_file(
     filepath: &str,
-    source_code: String,
-) -> Result<String, Box<dyn Error>> {
-    let tokens = phronima::tokenize_source_code(filepath, &source_code);
-    let parsed_tokens = phronima::parse_tokens(tokens)?;
-    let mut program = phronima::parse_program_structure(parsed_tokens)?;
-    for (_fname, fblock) in &mut program.functions {
-        phronima::create_references_for_blocks(fblock);
-    }
-    let bf_code = compile_program(program)?;
-    Ok(bf_code)
+    search_path: &[PathBuf],
+    config: bf::Config,
+    options: &CompileOptions,
+) -> Result<(), Box<dyn Error>> {
+    let bf_code = if filepath.ends_with(".phron") {
+        let program = frontend::read_program(filepath, search_path)?;
+        compile_program(program, options)?
+    } else {
+        fs::read_to_string(filepath)?
+    };
+    bf::run(
+        &bf_code,
+        config,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+    )?;
+    Ok(())
 }
 
 fn write_program_to_file(filepath: &str, compiled_code: String) -> Result<(), Box<dyn Error>> {
     let mut file = File::create(filepath)?;
-    file.write_all(&compiled_code.as_bytes())?;
+    file.write_all(compiled_code.as_bytes())?;
     Ok(())
 }
 
-fn compile_program(program: Program) -> Result<String, Box<dyn Error>> {
-    let mut compiled_code: String = String::from("");
-    let program = program.functions;
+// Adds the current cell to the cell at each offset from it and clears it, leaving the pointer
+// where it was
+fn push_move(code: &mut ir::Builder, offsets: &[i64]) {
+    code.open_loop();
+    code.add(-1);
+    let mut pointer = 0;
+    for offset in offsets {
+        code.move_by(offset - pointer);
+        code.add(1);
+        pointer = *offset;
+    }
+    code.move_by(-pointer);
+    code.close_loop().unwrap();
+}
 
-    let mut call_stack: Vec<(String, usize)> = vec![];
-    let mut current_function = program.get("main").unwrap();
-    let mut current_function_name: String = "main".to_string();
+// a -> a a
+// The value is moved to the two cells above it, and the second copy is moved back into place
+fn push_dup(code: &mut ir::Builder) {
+    push_move(code, &[1, 2]);
+    code.move_by(2);
+    push_move(code, &[-2]);
+    code.move_by(-1);
+}
 
-    let mut memory_initialized = false;
-    let memory_cell_size = 4;
+// a b -> b a, using the cell above b
+fn push_swap(code: &mut ir::Builder) {
+    code.move_by(-1);
+    push_move(code, &[2]);
+    code.move_by(1);
+    push_move(code, &[-1]);
+    code.move_by(1);
+    push_move(code, &[-1]);
+    code.move_by(-1);
+}
 
-    let mut i = 0;
-    while i < current_function.len() {
-        match &current_function[i] {
-            Function::Push(byte) => {
-                compiled_code.push('>');
-                for _i in 0..*byte {
-                    compiled_code.push('+');
+// a b -> (a > b)
+// Decrements a and b together until a reaches 0. If b reaches 0 first the result (3 cells above
+// b) is set to 1 and a is set to 1 to end the loop. The two cells above b are used for the
+// "if b is 0" check
+fn push_greater_than(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    code.move_by(-1);
+    code.open_loop();
+    code.move_by(2);
+    code.add(1);
+    code.move_by(-1);
+    code.open_loop();
+    code.move_by(1);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.open_loop();
+    code.move_by(2);
+    code.add(1);
+    code.move_by(-4);
+    code.clear();
+    code.add(1);
+    code.move_by(2);
+    code.add(-1);
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(-2);
+    code.add(-1);
+    code.move_by(-1);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.clear();
+    code.move_by(3);
+    push_move(code, &[-4]);
+    code.move_by(-4);
+    Ok(())
+}
+
+// a b -> (a / b) (a % b)
+// Counts a down into a remainder (1 cell above b) and a countdown (3 cells above b) that starts at
+// b. Whenever the countdown reaches 0 the remainder is cleared, the quotient (2 cells above b) is
+// incremented and the countdown starts over. If b is 0 the countdown never reaches 0, leaving a
+// quotient of 0 and a remainder of a
+fn push_divmod(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    // b is copied to the countdown through the cell 6 above it
+    push_move(code, &[3, 6]);
+    code.move_by(6);
+    push_move(code, &[-6]);
+    code.move_by(-7);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(2);
+    code.add(1);
+    code.move_by(2);
+    code.add(-1);
+    // The cell above the countdown is set to 1 and cleared if the countdown isn't 0
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-1);
+    code.open_loop();
+    code.move_by(1);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.open_loop();
+    code.move_by(-3);
+    code.clear();
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-2);
+    push_move(code, &[3, 6]);
+    code.move_by(6);
+    push_move(code, &[-6]);
+    code.move_by(-2);
+    code.add(-1);
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(-6);
+    code.close_loop()?;
+    // The quotient and remainder are moved down in place of a and b
+    code.move_by(1);
+    code.clear();
+    code.move_by(3);
+    code.clear();
+    code.move_by(-1);
+    push_move(code, &[-3]);
+    code.move_by(-1);
+    push_move(code, &[-1]);
+    code.move_by(-1);
+    Ok(())
+}
+
+// Sets the cell above a character to 1 if it is a digit, leaving the pointer there
+fn push_digit_check(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    push_dup(code);
+    code.add(-48);
+    code.move_by(1);
+    code.add(10);
+    push_swap(code);
+    push_greater_than(code)
+}
+
+// 'strin' reads one character at a time into the cell above the string read so far. The next
+// character is checked by setting the cell 2 above it to 1 if it isn't 0 (end of input) or a new
+// line, leaving the pointer there
+fn push_string_in_check(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    push_dup(code);
+    code.open_loop();
+    code.add(-10);
+    code.open_loop();
+    code.clear();
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-1);
+    code.close_loop()?;
+    code.close_loop()?;
+    code.move_by(1);
+    Ok(())
+}
+
+// Every character read is inserted at the bottom of the string, just above the NULL character,
+// so the first character read ends up on top. The character is moved 2 cells up and the string
+// is shifted up by one. The character is then carried down one at a time, walking left over the
+// string (which never contains 0) to the NULL character and back
+fn push_string_in_insert(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    push_move(code, &[2]);
+    code.move_by(-1);
+    code.open_loop();
+    push_move(code, &[1]);
+    code.move_by(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.add(1);
+    code.open_loop();
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.add(-1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(-2);
+    code.open_loop();
+    code.move_by(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.add(1);
+    code.open_loop();
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.close_loop()?;
+    Ok(())
+}
+
+// Prints the top of the stack as a decimal number, leaving it in place. It is divided by 10 twice
+// for its digits, which are printed by adding 48. Leading zeros are skipped, but a 0 is always
+// printed for the ones
+fn push_number_out(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    // a is divided by 10
+    code.move_by(2);
+    code.add(10);
+    code.move_by(-2);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(1);
+    code.add(-1);
+    code.open_loop();
+    code.move_by(1);
+    code.add(1);
+    code.move_by(2);
+    code.close_loop()?;
+    code.move_by(1);
+    code.open_loop();
+    code.add(1);
+    push_move(code, &[-1]);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(2);
+    code.close_loop()?;
+    code.move_by(-6);
+    code.close_loop()?;
+    // The quotient is divided by 10 again, for the tens and the hundreds
+    code.move_by(2);
+    code.clear();
+    code.move_by(3);
+    code.add(10);
+    code.move_by(-1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(1);
+    code.add(-1);
+    code.open_loop();
+    code.move_by(1);
+    code.add(1);
+    code.move_by(2);
+    code.close_loop()?;
+    code.move_by(1);
+    code.open_loop();
+    code.add(1);
+    push_move(code, &[-1]);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(2);
+    code.close_loop()?;
+    code.move_by(-5);
+    code.close_loop()?;
+    code.move_by(1);
+    code.clear();
+    // The hundreds, if there are any
+    code.move_by(2);
+    code.open_loop();
+    code.move_by(1);
+    code.add(6);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(-1);
+    code.add(8);
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(-1);
+    code.output();
+    code.move_by(-2);
+    code.add(1);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(1);
+    code.clear();
+    code.close_loop()?;
+    // The tens, if there are any or there were hundreds
+    code.move_by(-1);
+    code.open_loop();
+    code.move_by(-1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(1);
+    code.add(-1);
+    code.move_by(-1);
+    code.close_loop()?;
+    code.add(6);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(1);
+    code.add(8);
+    code.move_by(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.output();
+    code.clear();
+    code.close_loop()?;
+    // The ones
+    code.move_by(-2);
+    code.add(6);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(-1);
+    code.add(8);
+    code.move_by(1);
+    code.close_loop()?;
+    code.move_by(-1);
+    code.output();
+    code.clear();
+    code.move_by(-2);
+    push_move(code, &[-1]);
+    code.move_by(-1);
+    Ok(())
+}
+
+// Carries a 1 over empty cells in a direction until the next cell isn't empty, leaving the
+// pointer on the last empty cell. It starts on an empty cell, and the cell after that must be
+// empty too. The cells between the top of the stack and the memory are empty, so this is how the
+// pointer gets from one to the other
+fn push_seek(code: &mut ir::Builder, direction: i64) {
+    code.add(1);
+    code.open_loop();
+    code.move_by(direction);
+    code.open_loop();
+    code.move_by(-direction);
+    code.add(-1);
+    code.close_loop().unwrap();
+    code.move_by(-direction);
+    push_move(code, &[direction]);
+    code.move_by(direction);
+    code.close_loop().unwrap();
+}
+
+// a b -> writes b to address a
+// A 1 is placed above the stack for the walks back to stop at. a is counted down into 2 cells of
+// the first memory cell, and b into a third, walking there and back for each count. Then the three
+// are moved up a memory cell at a time, counting down one of the copies of a, until they reach
+// memory cell a. b is written there and the other copy of a counts the way back down
+fn push_write(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-2);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(3);
+    push_seek(code, 1);
+    code.move_by(3);
+    code.add(1);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-4);
+    push_seek(code, -1);
+    code.move_by(-3);
+    code.close_loop()?;
+    code.move_by(1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(2);
+    push_seek(code, 1);
+    code.move_by(2);
+    code.add(1);
+    code.move_by(-2);
+    push_seek(code, -1);
+    code.move_by(-2);
+    code.close_loop()?;
+    // Up to memory cell a
+    code.move_by(2);
+    push_seek(code, 1);
+    code.move_by(4);
+    code.open_loop();
+    code.move_by(-1);
+    push_move(code, &[4]);
+    code.move_by(-1);
+    push_move(code, &[4]);
+    code.move_by(2);
+    push_move(code, &[4]);
+    code.move_by(4);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    code.clear();
+    code.move_by(-3);
+    push_move(code, &[3]);
+    // And back
+    code.move_by(1);
+    code.open_loop();
+    push_move(code, &[-4]);
+    code.move_by(-4);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(-3);
+    push_seek(code, -1);
+    code.move_by(-1);
+    code.add(-1);
+    code.move_by(-3);
+    Ok(())
+}
+
+// a -> reads the byte at address a
+// a is carried up to memory cell a the same way as in write, without b. The byte there is copied,
+// and the copy is carried back down to the first memory cell. From there it is counted onto the
+// stack in place of a, walking there and back for each count
+fn push_read(code: &mut ir::Builder) -> Result<(), Box<dyn Error>> {
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(2);
+    push_seek(code, 1);
+    code.move_by(3);
+    code.add(1);
+    code.move_by(1);
+    code.add(1);
+    code.move_by(-4);
+    push_seek(code, -1);
+    code.move_by(-2);
+    code.close_loop()?;
+    // Up to memory cell a
+    code.move_by(2);
+    push_seek(code, 1);
+    code.move_by(4);
+    code.open_loop();
+    code.move_by(-1);
+    push_move(code, &[4]);
+    code.move_by(1);
+    push_move(code, &[4]);
+    code.move_by(4);
+    code.add(-1);
+    code.close_loop()?;
+    code.move_by(1);
+    push_move(code, &[-1, -3]);
+    code.move_by(-1);
+    push_move(code, &[1]);
+    // And back
+    code.move_by(-1);
+    code.open_loop();
+    code.move_by(-1);
+    push_move(code, &[-4]);
+    code.move_by(1);
+    push_move(code, &[-4]);
+    code.move_by(-4);
+    code.add(-1);
+    code.close_loop()?;
+    // The byte is counted onto the stack
+    code.move_by(-1);
+    code.open_loop();
+    code.add(-1);
+    code.move_by(-2);
+    push_seek(code, -1);
+    code.move_by(-2);
+    code.add(1);
+    code.move_by(2);
+    push_seek(code, 1);
+    code.move_by(2);
+    code.close_loop()?;
+    code.move_by(-2);
+    push_seek(code, -1);
+    code.move_by(-1);
+    code.add(-1);
+    code.move_by(-1);
+    Ok(())
+}
+
+// Operations that are not control flow, these are the steps recorded when tracing a program
+fn is_traced(function: &Function) -> bool {
+    !matches!(
+        function,
+        Function::If(_)
+            | Function::Else(_)
+            | Function::End(_)
+            | Function::While(_)
+            | Function::FunctionDeclaration(_, _)
+            | Function::Override()
+            | Function::Private()
+            | Function::FunctionCall(_)
+            | Function::Import(_, _)
+            | Function::Variable(_, _)
+    )
+}
+
+// How the segment of a program that is called through the dispatch loop ends
+enum Exit {
+    Jump(usize),
+    // Continues with the first segment if the top of the stack isn't 0, otherwise the second
+    Branch(usize, usize),
+    // Places the id of the return segment below the inputs of the function and continues with
+    // the first segment of the function
+    Call {
+        inputs: usize,
+        entry: usize,
+        ret: usize,
+    },
+    // Continues with the segment whose id is below the outputs of the function
+    Return(usize),
+    Halt,
+}
+
+struct Segment {
+    code: Vec<ir::Node>,
+    exit: Exit,
+}
+
+// An operation of a function being compiled, with the name of the function it is from (which
+// differs for inlined functions) and where it was written
+type Operation<'a> = (&'a Function, &'a str, Option<&'a Location>);
+
+// The two segments that follow the start of a block that contains a call: the if and else
+// branches, the else branch and the end, or the loop body and the end of the loop
+type BlockSegments = Option<(usize, usize)>;
+
+// Every iteration of the dispatch loop counts down the id of the next segment, running the
+// segment whose turn it reaches 0 on. The segment is entered with its id and the flag above it
+// cleared, so the cells above the stack are left empty while it runs.
+// A segment has to add the number of cases after it to the id it continues with, as the rest of
+// the countdown still runs, and together they must fit in a byte. So the cases are split into
+// groups of at most this many, and groups into groups of their own, as many times as it takes.
+// An id has a digit for each level, which counts down the groups or cases of that level
+const DISPATCH_GROUP_SIZE: usize = 128;
+
+// The levels of the dispatch loop for a number of cases, the last of which halts the program.
+// The digits of an id are kept in the cells above the top of the stack, the first level's
+// nearest, with the flag of the case being counted down above them
+struct Dispatch {
+    digits: usize,
+    cases: usize,
+}
+
+// Compiles every function with a known stack effect once. Its operations are split into
+// segments at each call, and each block that contains a call is split into segments too, and
+// the program runs as a loop that picks the next segment by the id kept above the top of the
+// stack. A call places the id of the segment following it below the function's inputs, which
+// the function moves back above its outputs when it returns.
+// Functions with an unknown stack effect, or that use '?', are inlined where they are called
+struct CodeGenerator<'a> {
+    program: &'a Program,
+    effects: HashMap<String, StackEffect>,
+    options: &'a CompileOptions,
+    inlined: HashSet<&'a str>,
+    // Whether a function reachable from main calls 'initmem'
+    uses_memory: bool,
+    memory_initialized: bool,
+    // The first segment of every function called through the dispatch loop
+    entries: HashMap<&'a str, usize>,
+    queue: VecDeque<&'a str>,
+    // Segments are numbered from 1, in the order they are created
+    segments: Vec<Option<Segment>>,
+}
+
+fn compile_program(
+    mut program: Program,
+    options: &CompileOptions,
+) -> Result<String, Box<dyn Error>> {
+    if options.optimize {
+        phronima::fold_constants(&mut program)?;
+    }
+    let effects = check::stack_effects(&program)?;
+    let mut generator = CodeGenerator::new(&program, effects, options)?;
+    let main = generator.new_segment();
+    generator.compile_function("main", main)?;
+    while let Some(function_name) = generator.queue.pop_front() {
+        let entry = generator.entries[function_name];
+        generator.compile_function(function_name, entry)?;
+    }
+    let mut code = generator.finish()?;
+    if options.optimize {
+        code = ir::optimize(code);
+    }
+    Ok(match options.target {
+        Target::Bf => ir::to_bf(&code),
+        Target::C => ir::to_c(&code, bf::TAPE_SIZE),
+    })
+}
+
+impl<'a> CodeGenerator<'a> {
+    fn new(
+        program: &'a Program,
+        effects: HashMap<String, StackEffect>,
+        options: &'a CompileOptions,
+    ) -> Result<CodeGenerator<'a>, Box<dyn Error>> {
+        let functions = &program.functions;
+        let mut reachable: Vec<&str> = vec!["main"];
+        let mut i = 0;
+        while i < reachable.len() {
+            for operation in &functions[reachable[i]] {
+                if let Function::FunctionCall(callee) = operation {
+                    let (callee, _) = functions
+                        .get_key_value(callee)
+                        .ok_or(format!("Unknown function: {}", callee))?;
+                    if !reachable.contains(&callee.as_str()) {
+                        reachable.push(callee);
+                    }
                 }
             }
-            Function::Pop() => {
-                compiled_code.push_str("[-]<");
-            }
-            Function::Plus() => {
-                compiled_code.push_str("[<+>-]<");
-            }
-            Function::Minus() => {
-                compiled_code.push_str("[-<->]<");
-            }
-            Function::Mult() => {
-                compiled_code.push_str("<[->>+<<]>[->[->+<<<+>>]>[-<+>]<<]>[-]<<");
-            }
-            Function::CharOut() => {
-                compiled_code.push_str(".[-]<");
-            }
-            // Numout source:
-            // https://esolangs.org/wiki/Brainfuck_algorithms#Print_value_of_cell_x_as_number_(8-bit)
-            Function::NumOut() => {
-                compiled_code.push_str(">>++++++++++<<[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]>>[-]>>>++++++++++<[->-[>+>>]>[+[- <+>]>+>>]<<<<<]>[-]>>[>++++++[-<++++++++>]<.<<+>+>[-]]<[<[->-<]++++++[->++++++++ <]>.[-]]<<++++++[-<++++++++>]<.[-]<<[-<+>]<");
-                compiled_code.push_str("[-]<");
-            }
-            Function::Write() => {
-                if !memory_initialized {
-                    eprintln!("You must first call 'initmem' before trying to access the memory");
-                } else {
-                    compiled_code.push_str(">+<<[->>>+[>[<-]<[->+<]>]>>>+>+<<<<+[<[>-]>[-<+>]<]<<<]>[->>+[>[<-]<[->+<]>]>>+<<+[<[>-]>[-<+>]<]<<]>>+[>[<-]<[->+<]>]>>>>[<[->>>>+<<<<]<[->>>>+<<<<]>>[>>>>+<<<<-]>>>>-]<<[->>>+<<<]>[[<<<<+>>>>-]<<<<-]<<<+[<[>-]>[-<+>]<]<-<<<");
+            i += 1;
+        }
+
+        let inlined = reachable
+            .iter()
+            .copied()
+            .filter(|name| {
+                effects.get(*name).copied().flatten().is_none()
+                    || uses_stack_height(functions, name, &mut HashSet::new())
+            })
+            .collect();
+        let uses_memory = reachable
+            .iter()
+            .any(|name| functions[*name].contains(&Function::InitMem()));
+
+        Ok(CodeGenerator {
+            program,
+            effects,
+            options,
+            inlined,
+            uses_memory,
+            memory_initialized: false,
+            entries: HashMap::new(),
+            queue: VecDeque::new(),
+            segments: vec![],
+        })
+    }
+
+    fn new_segment(&mut self) -> usize {
+        self.segments.push(None);
+        self.segments.len()
+    }
+
+    fn end_segment(
+        &mut self,
+        segment: usize,
+        code: &mut ir::Builder,
+        exit: Exit,
+    ) -> Result<(), Box<dyn Error>> {
+        self.segments[segment - 1] = Some(Segment {
+            code: std::mem::take(code).finish()?,
+            exit,
+        });
+        Ok(())
+    }
+
+    // Pushed bytes are added one at a time, unless the shortest code can be used
+    fn push_byte(&self, code: &mut ir::Builder, byte: u8, below: Option<u8>) {
+        if self.options.optimize && self.options.cell_width == bf::CellWidth::U8 {
+            push_byte(code, byte, below);
+        } else {
+            code.add(byte as i64);
+        }
+    }
+
+    // The first segment of a function, which is compiled later if it hasn't been already
+    fn entry(&mut self, function_name: &'a str) -> usize {
+        if let Some(entry) = self.entries.get(function_name) {
+            return *entry;
+        }
+        let entry = self.new_segment();
+        self.entries.insert(function_name, entry);
+        self.queue.push_back(function_name);
+        entry
+    }
+
+    // The operations of a function with the functions it inlines expanded
+    fn flatten(
+        &self,
+        function_name: &'a str,
+        inlining: &mut Vec<&'a str>,
+        operations: &mut Vec<Operation<'a>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let locations = self.program.locations.get(function_name);
+        for (i, operation) in self.program.functions[function_name].iter().enumerate() {
+            let location = locations.and_then(|locations| locations.get(i));
+            match operation {
+                Function::FunctionCall(callee) if self.inlined.contains(callee.as_str()) => {
+                    let (callee, _) = self.program.functions.get_key_value(callee).unwrap();
+                    if inlining.contains(&callee.as_str()) {
+                        Err(Diagnostic::error(format!(
+                            "function '{}' is recursive, so it must declare a signature such as \
+                             '( a -- b )' and can't use '?'",
+                            self.program.display_name(callee)
+                        ))
+                        .at_some(location.cloned()))?;
+                    }
+                    inlining.push(callee);
+                    self.flatten(callee, inlining, operations)?;
+                    inlining.pop();
                 }
+                _ => operations.push((operation, function_name, location)),
             }
-            Function::Read() => {
-                if !memory_initialized {
-                    eprintln!("You must first call 'initmem' before trying to access the memory");
-                } else {
-                    compiled_code.push_str(">+<[->>+[>[<-]<[->+<]>]>>>+>+<<<<+[<[>-]>[-<+>]<]<<]>>+[>[<-]<[->+<]>]>>>>[<[->>>>+<<<<]>[>>>>+<<<<-]>>>>-]>[-<+<<+>>>]<[->+<]<[<[-<<<<+>>>>]>[<<<<+>>>>-]<<<<-]<[-<<+[<[>-]>[-<+>]<]<<+>>+[>[<-]<[->+<]>]>>]<<+[<[>-]>[-<+>]<]<-<");
+        }
+        Ok(())
+    }
+
+    fn compile_function(&mut self, name: &'a str, entry: usize) -> Result<(), Box<dyn Error>> {
+        let mut operations: Vec<Operation> = vec![];
+        self.flatten(name, &mut vec![name], &mut operations)?;
+        let lowered = lowered_blocks(&operations);
+
+        let mut code = ir::Builder::new();
+        let mut segment = entry;
+
+        // The kind of each open block, if and else blocks leave a flag above the stack.
+        // Alongside is the stack height when the block was opened (for else blocks, the height at
+        // the end of the if block), and the segments that follow it if it contains a call
+        let mut open_blocks: Vec<(&Function, Option<usize>, BlockSegments)> = vec![];
+        // The stack height is tracked for '?', it becomes unknown after a block that changes it
+        let mut stack_height: Option<usize> = if name == "main" { Some(0) } else { None };
+
+        for (i, &(operation, function_name, location)) in operations.iter().enumerate() {
+            match operation {
+                // The condition is copied above the top of the stack and a flag for the else block
+                // is placed above that. Both are cleared before the block runs, so the block sees
+                // the same stack as in the simulator. Blocks must leave the stack height unchanged
+                Function::If(_index) => {
+                    let segments = if lowered[i] {
+                        let (then, other) = (self.new_segment(), self.new_segment());
+                        self.end_segment(segment, &mut code, Exit::Branch(then, other))?;
+                        segment = then;
+                        Some((then, other))
+                    } else {
+                        push_dup(&mut code);
+                        code.move_by(1);
+                        code.add(1);
+                        code.move_by(-1);
+                        code.open_loop();
+                        code.clear();
+                        code.move_by(1);
+                        code.add(-1);
+                        code.move_by(-2);
+                        None
+                    };
+                    open_blocks.push((operation, stack_height, segments));
                 }
-            }
-            Function::Mem() => {
-                compiled_code.push_str(">");
-            }
-            Function::InitMem() => {
-                if !memory_initialized {
-                    for _i in 0..(30000 - 256 * memory_cell_size - 1) {
-                        compiled_code.push('>');
-                    }
-                    for _i in 0..82 {
-                        compiled_code.push('+');
-                    }
-                    for _i in 0..(30000 - 256 * memory_cell_size - 1) {
-                        compiled_code.push('<');
+                Function::Else(_index) => {
+                    if let Some((_, if_height, segments)) = open_blocks.pop() {
+                        let segments = match segments {
+                            Some((_, other)) => {
+                                let end = self.new_segment();
+                                self.end_segment(segment, &mut code, Exit::Jump(end))?;
+                                segment = other;
+                                Some((other, end))
+                            }
+                            None => {
+                                code.move_by(1);
+                                code.close_loop()?;
+                                code.move_by(1);
+                                code.open_loop();
+                                code.add(-1);
+                                code.move_by(-2);
+                                None
+                            }
+                        };
+                        open_blocks.push((operation, stack_height, segments));
+                        stack_height = if_height;
                     }
-                    memory_initialized = true;
-                } else {
-                    eprintln!("Memory has already been initialized");
                 }
-            }
-            Function::If(_index) => {
-                compiled_code.push_str("[->+>+<<]>>[-<<+>>]<");
-                compiled_code.push('[');
-            }
-            Function::End(index) => {
-                if index.unwrap() == current_function.len() {
-                    compiled_code.push_str("[-]]<");
-                } else if index.unwrap() > current_function.len() {
-                    eprintln!("Something really not great happened here...");
-                    process::exit(1);
-                } else {
-                    match current_function[index.unwrap()] {
-                        Function::While(_index) => {
-                            compiled_code.push(']');
+                Function::End(_index) => {
+                    let Some((block, block_height, segments)) = open_blocks.pop() else {
+                        Err(format!("Unmatched 'end' in function {}", function_name))?
+                    };
+                    match (block, segments) {
+                        (Function::While(_), None) => code.close_loop()?,
+                        (Function::While(_), Some((body, after))) => {
+                            let exit = Exit::Branch(body, after);
+                            self.end_segment(segment, &mut code, exit)?;
+                            segment = after;
+                        }
+                        (Function::Else(_), None) => {
+                            code.move_by(2);
+                            code.close_loop()?;
+                            code.move_by(-2);
+                        }
+                        (_, None) => {
+                            code.move_by(1);
+                            code.close_loop()?;
+                            code.move_by(1);
+                            code.clear();
+                            code.move_by(-2);
                         }
-                        _ => {
-                            compiled_code.push_str("[-]]<"); // This moves the cell pointer to a block
-                            // with a value of 0 to ensure the if block never executes more than once
+                        (_, Some((_, next))) => {
+                            self.end_segment(segment, &mut code, Exit::Jump(next))?;
+                            segment = next;
                         }
                     }
+                    if stack_height != block_height {
+                        stack_height = None;
+                    }
                 }
-            }
-            // TODO: fix the behaviour of if and else incorrectly moving the stack
-            Function::Else(_index) => {
-                compiled_code.push_str("[-]]<");
-                compiled_code.push_str("[->+>+<<]>>[-<<+>>]<");
-                compiled_code.push_str(">[-]<-[>-<-]>[<+>-]<");
-                compiled_code.push('[');
-            }
-            Function::While(_index) => {
-                compiled_code.push('[');
-            }
-            Function::LessThan() => {
-                todo!("lessthan compiler code");
-            }
-            Function::GreaterThan() => {
-                todo!("greaterthan compiler code");
-            }
-            Function::Equals() => {
-                compiled_code.push_str("<[>>>+<<<-]+>[>>-<+<-]>[<+>-]>[<<<->>>[-]]<<[-]<");
-            }
-            Function::Swap() => {
-                compiled_code.push_str("<[->>+<<]>[-<+>]>[-<+>]<");
-            }
-            Function::Pull(number) => {
-                for _i in 0..*number {
-                    compiled_code.push('<');
+                Function::While(_index) => {
+                    let segments = if lowered[i] {
+                        let (body, after) = (self.new_segment(), self.new_segment());
+                        self.end_segment(segment, &mut code, Exit::Branch(body, after))?;
+                        segment = body;
+                        Some((body, after))
+                    } else {
+                        code.open_loop();
+                        None
+                    };
+                    open_blocks.push((operation, stack_height, segments));
+                }
+                Function::FunctionCall(callee) => {
+                    let (callee, _) = self.program.functions.get_key_value(callee).unwrap();
+                    let (inputs, outputs) = self.effects[callee].unwrap();
+                    let entry = self.entry(callee);
+                    let ret = self.new_segment();
+                    let exit = Exit::Call { inputs, entry, ret };
+                    self.end_segment(segment, &mut code, exit)?;
+                    segment = ret;
+                    stack_height = stack_height
+                        .and_then(|height| height.checked_sub(inputs))
+                        .map(|height| height + outputs);
+                }
+                Function::Push(byte) => {
+                    code.move_by(1);
+                    self.push_byte(&mut code, *byte, None);
+                }
+                Function::Pop() => {
+                    code.clear();
+                    code.move_by(-1);
+                }
+                Function::Plus() => {
+                    push_move(&mut code, &[-1]);
+                    code.move_by(-1);
+                }
+                Function::Minus() => {
+                    code.open_loop();
+                    code.add(-1);
+                    code.move_by(-1);
+                    code.add(-1);
+                    code.move_by(1);
+                    code.close_loop()?;
+                    code.move_by(-1);
+                }
+                // a is moved above b, and for every count of b, a is added to the product in
+                // place of a and to the cell above, from which it is moved back
+                Function::Mult() => {
+                    code.move_by(-1);
+                    push_move(&mut code, &[2]);
+                    code.move_by(1);
+                    code.open_loop();
+                    code.add(-1);
+                    code.move_by(1);
+                    push_move(&mut code, &[1, -2]);
+                    code.move_by(1);
+                    push_move(&mut code, &[-1]);
+                    code.move_by(-2);
+                    code.close_loop()?;
+                    code.move_by(1);
+                    code.clear();
+                    code.move_by(-2);
+                }
+                Function::Div() => {
+                    push_divmod(&mut code)?;
+                    code.clear();
+                    code.move_by(-1);
                 }
-                compiled_code.push_str("[-");
-                for _i in 0..number + 1 {
-                    compiled_code.push('>');
+                Function::Mod() => {
+                    push_divmod(&mut code)?;
+                    code.move_by(-1);
+                    code.clear();
+                    code.move_by(1);
+                    push_move(&mut code, &[-1]);
+                    code.move_by(-1);
                 }
-                compiled_code.push_str("+>+");
-                for _i in 0..number + 2 {
-                    compiled_code.push('<');
+                Function::DivMod() => {
+                    push_divmod(&mut code)?;
                 }
-                compiled_code.push(']');
-                for _i in 0..number + 2 {
-                    compiled_code.push('>');
+                Function::CharOut() => {
+                    code.output();
+                    code.clear();
+                    code.move_by(-1);
                 }
-                compiled_code.push_str("[-");
-                for _i in 0..number + 2 {
-                    compiled_code.push('<');
+                Function::CharIn() => {
+                    code.move_by(1);
+                    code.input();
                 }
-                compiled_code.push('+');
-                for _i in 0..number + 2 {
-                    compiled_code.push('>');
+                // The number is built in the cell above the top, and is multiplied by 10 through
+                // the cell 2 above it before each digit is added
+                Function::NumIn() => {
+                    code.move_by(2);
+                    code.input();
+                    push_digit_check(&mut code)?;
+                    code.open_loop();
+                    code.clear();
+                    code.move_by(-1);
+                    code.add(-48);
+                    code.move_by(-1);
+                    code.open_loop();
+                    code.add(-1);
+                    code.move_by(2);
+                    code.add(10);
+                    code.move_by(-2);
+                    code.close_loop()?;
+                    code.move_by(2);
+                    push_move(&mut code, &[-2]);
+                    code.move_by(-1);
+                    push_move(&mut code, &[-1]);
+                    code.input();
+                    push_digit_check(&mut code)?;
+                    code.close_loop()?;
+                    code.move_by(-1);
+                    code.clear();
+                    code.move_by(-1);
+                }
+                Function::StringIn() => {
+                    code.move_by(2);
+                    code.input();
+                    push_string_in_check(&mut code)?;
+                    code.open_loop();
+                    code.add(-1);
+                    code.move_by(-2);
+                    push_string_in_insert(&mut code)?;
+                    code.move_by(-1);
+                    code.input();
+                    push_string_in_check(&mut code)?;
+                    code.close_loop()?;
+                    code.move_by(-2);
+                    code.clear();
+                    code.move_by(-1);
+                    stack_height = None;
+                }
+                // Numout source:
+                // https://esolangs.org/wiki/Brainfuck_algorithms#Print_value_of_cell_x_as_number_(8-bit)
+                Function::NumOut() => {
+                    push_number_out(&mut code)?;
+                    code.clear();
+                    code.move_by(-1);
+                }
+                Function::Write() => {
+                    if !self.uses_memory {
+                        Err(memory_not_initialized(location))?;
+                    } else {
+                        push_write(&mut code)?;
+                    }
+                }
+                Function::Read() => {
+                    if !self.uses_memory {
+                        Err(memory_not_initialized(location))?;
+                    } else {
+                        push_read(&mut code)?;
+                    }
+                }
+                Function::Mem() => {
+                    code.move_by(1);
+                }
+                Function::InitMem() => {
+                    if !self.memory_initialized {
+                        code.move_by(MEMORY_START as i64);
+                        code.add(82);
+                        code.move_by(-(MEMORY_START as i64));
+                        self.memory_initialized = true;
+                    } else {
+                        report_warning(
+                            &Diagnostic::warning("the memory is initialized twice")
+                                .at_some(location.cloned())
+                                .with_note("only the first 'initmem' initializes the memory"),
+                        );
+                    }
+                }
+                Function::LessThan() => {
+                    push_swap(&mut code);
+                    push_greater_than(&mut code)?;
+                }
+                Function::GreaterThan() => {
+                    push_greater_than(&mut code)?;
+                }
+                // a is moved 2 cells above b and the result is set to 1 in its place. b is
+                // subtracted from the moved a, and the result is cleared if anything is left
+                Function::Equals() => {
+                    code.move_by(-1);
+                    push_move(&mut code, &[3]);
+                    code.add(1);
+                    code.move_by(1);
+                    code.open_loop();
+                    code.move_by(2);
+                    code.add(-1);
+                    code.move_by(-1);
+                    code.add(1);
+                    code.move_by(-1);
+                    code.add(-1);
+                    code.close_loop()?;
+                    code.move_by(1);
+                    push_move(&mut code, &[-1]);
+                    code.move_by(1);
+                    code.open_loop();
+                    code.move_by(-3);
+                    code.add(-1);
+                    code.move_by(3);
+                    code.clear();
+                    code.close_loop()?;
+                    code.move_by(-2);
+                    code.clear();
+                    code.move_by(-1);
+                }
+                Function::Swap() => {
+                    push_swap(&mut code);
+                }
+                Function::Pull(number) => {
+                    // The value is moved to the two cells above the top, and the second copy is
+                    // moved back into place
+                    let depth = *number as i64;
+                    code.move_by(-depth);
+                    push_move(&mut code, &[depth + 1, depth + 2]);
+                    code.move_by(depth + 2);
+                    push_move(&mut code, &[-(depth + 2)]);
+                    code.move_by(-1);
+                }
+                Function::Dup() => {
+                    push_dup(&mut code);
+                }
+                // Both values are moved to the 4 cells above, and the second copies are moved
+                // back into place
+                Function::TwoDup() => {
+                    code.move_by(-1);
+                    push_move(&mut code, &[2, 4]);
+                    code.move_by(1);
+                    push_move(&mut code, &[2, 4]);
+                    code.move_by(3);
+                    push_move(&mut code, &[-4]);
+                    code.move_by(1);
+                    push_move(&mut code, &[-4]);
+                    code.move_by(-2);
+                }
+                Function::GetStackHeight() => match stack_height {
+                    Some(height) => {
+                        code.move_by(1);
+                        self.push_byte(&mut code, height as u8, None);
+                    }
+                    None => {
+                        Err(Diagnostic::error(format!(
+                            "the stack height used by '?' in '{}' is not known at compile time",
+                            self.program.display_name(function_name)
+                        ))
+                        .at_some(location.cloned())
+                        .with_note(
+                            "a block or loop over a string before it changes the stack height",
+                        ))?;
+                    }
+                },
+                // 1 - a is counted down in the cell above and moved back into place
+                Function::Not() => {
+                    code.move_by(1);
+                    code.clear();
+                    code.move_by(-1);
+                    code.add(-1);
+                    code.open_loop();
+                    code.move_by(1);
+                    code.add(-1);
+                    code.move_by(-1);
+                    code.add(-1);
+                    code.close_loop()?;
+                    code.move_by(1);
+                    push_move(&mut code, &[-1]);
+                    code.move_by(-1);
+                }
+                // a is moved 2 cells above b. If it isn't 0 it is replaced by a copy of b, and the
+                // result in place of a is set to 1 if the copy isn't 0
+                Function::And() => {
+                    code.move_by(-1);
+                    push_move(&mut code, &[3]);
+                    code.move_by(3);
+                    code.open_loop();
+                    code.clear();
+                    code.move_by(-2);
+                    push_move(&mut code, &[2, 1]);
+                    code.move_by(1);
+                    push_move(&mut code, &[-1]);
+                    code.move_by(1);
+                    code.open_loop();
+                    code.move_by(-3);
+                    code.add(1);
+                    code.move_by(3);
+                    code.clear();
+                    code.close_loop()?;
+                    code.close_loop()?;
+                    code.move_by(-2);
+                    code.clear();
+                    code.move_by(-1);
+                }
+                Function::FunctionDeclaration(_, _)
+                | Function::Override()
+                | Function::Private() => {
+                    println!("This shouldn't be reachable");
+                }
+                Function::StringLiteral(string_literal) => {
+                    let byte_string = string_literal.as_slice();
+
+                    // Push 0 (NULL character) to the stack
+                    code.move_by(1);
+
+                    // Push each character in the string to the stack in reverse order
+                    let mut previous = None;
+                    for byte in byte_string.iter().rev() {
+                        code.move_by(1);
+                        self.push_byte(&mut code, *byte, previous);
+                        previous = Some(*byte);
+                    }
+                }
+                Function::Import(_, _) | Function::Variable(_, _) => {
+                    eprintln!("Unreachable");
                 }
-                compiled_code.push_str("]<");
             }
-            Function::Dup() => {
-                compiled_code.push_str("[->+>+<<]>>[-<<+>>]<");
+            if let Some((inputs, outputs)) = operation.stack_effect() {
+                stack_height = stack_height
+                    .and_then(|height| height.checked_sub(inputs))
+                    .map(|height| height + outputs);
             }
-            Function::TwoDup() => {
-                compiled_code
-                    .push_str("<[->>+>>+<<<<]>[->>+>>+<<<<]>>>[-<<<<+>>>>]>[-<<<<+>>>>]<<");
+            if self.options.trace && is_traced(operation) {
+                code.breakpoint();
             }
-            Function::GetStackHeight() => {
-                todo!("get stack height compiler code");
+        }
+
+        let exit = match self.effects[name] {
+            Some((_, outputs)) if name != "main" => Exit::Return(outputs),
+            _ => Exit::Halt,
+        };
+        self.end_segment(segment, &mut code, exit)?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<ir::Node>, Box<dyn Error>> {
+        let mut segments: Vec<Segment> = self.segments.into_iter().flatten().collect();
+        // A program without calls doesn't need the dispatch loop
+        if segments.len() == 1 {
+            return Ok(segments.remove(0).code);
+        }
+
+        // The last case ends the program, it is reached when a segment halts
+        let dispatch = Dispatch::new(segments.len() + 1);
+        let mut code = ir::Builder::new();
+        // The first segment is the start of main
+        code.move_by(1);
+        for digit in dispatch.id(1) {
+            code.add(digit as i64);
+            code.move_by(1);
+        }
+        code.move_by(-(dispatch.digits as i64));
+        code.open_loop();
+        dispatch.push_cases(&mut code, &mut segments.into_iter(), 1, 0)?;
+        code.close_loop()?;
+        code.move_by(-1);
+        code.finish()
+    }
+}
+
+impl Dispatch {
+    fn new(cases: usize) -> Dispatch {
+        let mut digits = 1;
+        while DISPATCH_GROUP_SIZE.pow(digits as u32) < cases {
+            digits += 1;
+        }
+        Dispatch { digits, cases }
+    }
+
+    // The number of cases in each group of a level, levels are counted from 0
+    fn group_size(&self, level: usize) -> usize {
+        DISPATCH_GROUP_SIZE.pow((self.digits - level - 1) as u32)
+    }
+
+    // The digits of the id of a case, cases are numbered from 1
+    fn id(&self, case: usize) -> Vec<usize> {
+        (0..self.digits)
+            .map(|level| (case - 1) / self.group_size(level) % DISPATCH_GROUP_SIZE + 1)
+            .collect()
+    }
+
+    // The number of groups or cases after the one a case is in, on each level
+    fn remaining(&self, case: usize) -> Vec<usize> {
+        let id = self.id(case);
+        (0..self.digits)
+            .map(|level| {
+                let size = self.group_size(level);
+                let parent = size * DISPATCH_GROUP_SIZE;
+                let start = (case - 1) / parent * parent;
+                let end = (start + parent).min(self.cases);
+                (end - start).div_ceil(size) - id[level]
+            })
+            .collect()
+    }
+
+    // Adds the groups of a level starting with the case first, until the end of the group they
+    // are in. Each starts and ends with the pointer on the digit of the level
+    fn push_cases(
+        &self,
+        code: &mut ir::Builder,
+        segments: &mut impl Iterator<Item = Segment>,
+        first: usize,
+        level: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let size = self.group_size(level);
+        let end = (first + size * DISPATCH_GROUP_SIZE).min(self.cases + 1);
+        let digits = self.digits as i64;
+        let digit = level as i64 + 1;
+        let flag = digits + 1 - digit;
+        for group in (first..end).step_by(size) {
+            // The flag is cleared if the digit doesn't reach 0, and the pointer is left above it
+            code.add(-1);
+            code.move_by(flag);
+            code.add(1);
+            code.move_by(-flag);
+            code.open_loop();
+            code.move_by(flag);
+            code.add(-1);
+            code.close_loop()?;
+            code.move_by(flag);
+            code.open_loop();
+            code.add(-1);
+            code.move_by(-(digits + 1));
+            if level + 1 < self.digits {
+                code.move_by(digit + 1);
+                self.push_cases(code, segments, group, level + 1)?;
+                code.move_by(digits + flag - digit);
+            } else if let Some(segment) = segments.next() {
+                code.append(segment.code);
+                self.push_exit(code, &segment.exit, group)?;
+            } else {
+                // The halting case leaves every digit at 0
+                code.move_by(digits + 2);
             }
-            Function::Not() => {
-                compiled_code.push_str(">[-]<-[>-<-]>[<+>-]<");
+            code.close_loop()?;
+            code.move_by(-(digits + 1 + flag - digit));
+        }
+        Ok(())
+    }
+
+    // Sets the digits of the id of a segment, starting from the first above the pointer and
+    // ending above the last
+    fn push_id(&self, code: &mut ir::Builder, digits: &[usize]) {
+        for digit in digits {
+            code.move_by(1);
+            code.add(*digit as i64);
+        }
+        code.move_by(1);
+    }
+
+    // Starts with the pointer at the top of the stack and ends 2 cells above the last digit of
+    // the new id
+    fn push_exit(
+        &self,
+        code: &mut ir::Builder,
+        exit: &Exit,
+        case: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let remaining = self.remaining(case);
+        let id = |segment: usize| -> Vec<usize> {
+            self.id(segment)
+                .iter()
+                .zip(&remaining)
+                .map(|(digit, remaining)| digit + remaining)
+                .collect()
+        };
+        let digits = self.digits as i64;
+        match exit {
+            Exit::Jump(next) => {
+                self.push_id(code, &id(*next));
+                code.move_by(1);
             }
-            Function::And() => {
-                compiled_code.push_str("<[>>>+<<<-]>>>[[-]<<[>>+<+<-]>[<+>-]>[<<<+>>>[-]]]<<[-]<");
+            // The condition is copied above the top with a flag above it, and the id is built
+            // above the flag before it is moved into place
+            Exit::Branch(then, other) => {
+                push_dup(code);
+                code.move_by(1);
+                code.add(1);
+                code.move_by(-1);
+                code.open_loop();
+                code.clear();
+                code.move_by(1);
+                code.add(-1);
+                self.push_id(code, &id(*then));
+                code.move_by(-(digits + 2));
+                code.close_loop()?;
+                code.move_by(1);
+                code.open_loop();
+                code.add(-1);
+                self.push_id(code, &id(*other));
+                code.move_by(-(digits + 1));
+                code.close_loop()?;
+                for _ in 0..digits {
+                    code.move_by(1);
+                    push_move(code, &[-2]);
+                }
             }
-            Function::FunctionDeclaration(_) => {
-                println!("This shouldn't be reachable");
+            // The inputs are moved up to make room for the return id
+            Exit::Call { inputs, entry, ret } => {
+                for _ in 0..*inputs {
+                    push_move(code, &[digits]);
+                    code.move_by(-1);
+                }
+                self.push_id(code, &self.id(*ret));
+                code.move_by(*inputs as i64 - 1);
+                self.push_id(code, &id(*entry));
+                code.move_by(1);
             }
-            // There's definitely a better way to do this
-            Function::FunctionCall(function_name) => {
-                call_stack.push((current_function_name.clone(), i));
-                i = 0;
-                current_function = program.get(&function_name.clone()).unwrap_or_else(|| {
-                    eprintln!("Unknown function: {}", function_name);
-                    process::exit(1);
-                });
-                current_function_name = function_name.clone();
-                continue;
+            // The return id is moved above the outputs and the outputs are moved down in its
+            // place, then the id is moved down above them
+            Exit::Return(outputs) => {
+                let outputs = *outputs as i64;
+                code.move_by(-(outputs + digits));
+                for _ in 0..digits {
+                    code.move_by(1);
+                    push_move(code, &[outputs + digits]);
+                }
+                for _ in 0..outputs + digits {
+                    code.move_by(1);
+                    push_move(code, &[-digits]);
+                }
+                code.move_by(-(digits * 2));
+                self.push_id(code, &remaining);
+                code.move_by(1);
             }
-            Function::StringLiteral(string_literal) => {
-                let byte_string = string_literal.as_bytes();
+            Exit::Halt => {
+                self.push_id(code, &id(self.cases));
+                code.move_by(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The ways push_byte can set a cell to a byte
+enum ByteCode {
+    // Adds to the cell, subtracting from 0 when that is shorter
+    Add(i64),
+    // Sets the cell above to the counter, and adds step to the cell that many times before
+    // adding the rest
+    Loop { counter: i64, step: i64, rest: i64 },
+    // Copies the byte below the cell and adds the difference
+    Copy(i64),
+}
+
+// The largest counter or step tried for a multiplication loop
+const MAX_LOOP_FACTOR: i64 = 32;
+
+// The shortest of the two ways to add to a byte that wraps at 256
+fn shortest_difference(difference: i64) -> i64 {
+    let difference = difference.rem_euclid(256);
+    if difference <= 128 {
+        difference
+    } else {
+        difference - 256
+    }
+}
+
+// Sets the current cell, which must be 0, to a byte using the shortest code: adding it one at a
+// time, subtracting from 0, a multiplication loop counted down in the cell above, or copying the
+// byte below when it is known and close. The cell above must be 0 and is left at 0.
+// All but adding a positive count rely on cells wrapping at 8 bits, as Minus and Not already do
+fn push_byte(code: &mut ir::Builder, byte: u8, below: Option<u8>) {
+    let byte = byte as i64;
+    let length = |byte_code: &ByteCode| match byte_code {
+        ByteCode::Add(count) => count.abs(),
+        // '>' counter '[<' step '>-]<' rest
+        ByteCode::Loop {
+            counter,
+            step,
+            rest,
+        } => counter + step.abs() + rest.abs() + 7,
+        // '<[->+>+<<]>>[-<<+>>]<' difference
+        ByteCode::Copy(difference) => difference.abs() + 21,
+    };
+
+    let mut best = ByteCode::Add(shortest_difference(byte));
+    let mut consider = |byte_code: ByteCode| {
+        if length(&byte_code) < length(&best) {
+            best = byte_code;
+        }
+    };
+    for counter in 2..=MAX_LOOP_FACTOR {
+        for step in (-MAX_LOOP_FACTOR..=MAX_LOOP_FACTOR).filter(|step| *step != 0) {
+            let rest = shortest_difference(byte - counter * step);
+            consider(ByteCode::Loop {
+                counter,
+                step,
+                rest,
+            });
+        }
+    }
+    if let Some(below) = below {
+        consider(ByteCode::Copy(shortest_difference(byte - below as i64)));
+    }
+
+    match best {
+        ByteCode::Add(count) => code.add(count),
+        ByteCode::Loop {
+            counter,
+            step,
+            rest,
+        } => {
+            code.move_by(1);
+            code.add(counter);
+            code.open_loop();
+            code.move_by(-1);
+            code.add(step);
+            code.move_by(1);
+            code.add(-1);
+            code.close_loop().unwrap();
+            code.move_by(-1);
+            code.add(rest);
+        }
+        ByteCode::Copy(difference) => {
+            code.move_by(-1);
+            push_dup(code);
+            code.add(difference);
+        }
+    }
+}
 
-                // Push 0 (NULL character) to the stack
-                compiled_code.push('>');
+// A memory operation in a program that never calls 'initmem'
+fn memory_not_initialized(location: Option<&Location>) -> Diagnostic {
+    Diagnostic::error("the memory is used before it is initialized")
+        .at_some(location.cloned())
+        .with_note("you must first call 'initmem' before trying to access the memory")
+}
 
-                // Push each character in the string to the stack in reverse order
-                for i in (0..byte_string.len()).rev() {
-                    compiled_code.push('>');
-                    for _i in 0..byte_string[i] {
-                        compiled_code.push('+');
+// Marks the blocks that contain a call, which have to be split into segments
+fn lowered_blocks(operations: &[Operation]) -> Vec<bool> {
+    let mut lowered = vec![false; operations.len()];
+    // The operations of each open block, and whether it contains a call
+    let mut open_blocks: Vec<(Vec<usize>, bool)> = vec![];
+    for (i, (operation, _, _)) in operations.iter().enumerate() {
+        match operation {
+            Function::If(_) | Function::While(_) => open_blocks.push((vec![i], false)),
+            Function::Else(_) => {
+                if let Some((block, _)) = open_blocks.last_mut() {
+                    block.push(i);
+                }
+            }
+            Function::End(_) => {
+                if let Some((mut block, contains_call)) = open_blocks.pop() {
+                    block.push(i);
+                    for index in block {
+                        lowered[index] = contains_call;
                     }
                 }
             }
-            Function::Import(_) => {
-                eprintln!("Unreachable");
+            Function::FunctionCall(_) => {
+                for (_, contains_call) in open_blocks.iter_mut() {
+                    *contains_call = true;
+                }
             }
+            _ => {}
         }
-        i += 1;
-        // and this as well
-        if i == current_function.len() && (&current_function_name != "main") {
-            let (fname, index) = call_stack.pop().unwrap();
-            i = index + 1;
-            current_function_name = fname.clone();
-            current_function = program.get(&fname).unwrap();
-        }
     }
-    Ok(compiled_code)
+    lowered
+}
+
+// Whether a function uses '?', directly or through a function it calls
+fn uses_stack_height<'a>(
+    functions: &'a HashMap<String, Vec<Function>>,
+    name: &'a str,
+    visited: &mut HashSet<&'a str>,
+) -> bool {
+    if !visited.insert(name) {
+        return false;
+    }
+    functions[name].iter().any(|operation| match operation {
+        Function::GetStackHeight() => true,
+        Function::FunctionCall(callee) => functions
+            .get_key_value(callee)
+            .is_some_and(|(callee, _)| uses_stack_height(functions, callee, visited)),
+        _ => false,
+    })
+}
+
+// Reads a single byte of input, the end of the input reads as 0 (the same as the interpreter)
+fn read_byte<R: Read>(input: &mut R) -> Result<u8, Box<dyn Error>> {
+    let mut byte = [0u8; 1];
+    match input.read(&mut byte)? {
+        0 => Ok(0),
+        _ => Ok(byte[0]),
+    }
 }
 
-fn simulate_program(program: Program) {
+// Returns the final stack and memory
+// on_step is called after every traced operation with the name of the function, the index of the
+// operation, the stack and the number of bytes written so far
+fn simulate_program<R: Read, W: Write>(
+    program: Program,
+    input: &mut R,
+    output: &mut W,
+    on_step: &mut dyn FnMut(&str, usize, &Stack, usize),
+) -> Result<(Stack, [u8; 256]), Box<dyn Error>> {
     let program = program.functions;
+    let mut output_len = 0;
 
     let mut stack: Stack = Stack::new();
     let mut call_stack: Vec<(String, usize)> = vec![];
@@ -314,7 +1701,17 @@ fn simulate_program(program: Program) {
     let mut memory: [u8; 256] = [0u8; 256];
 
     let mut i = 0;
-    while i < current_function.len() {
+    loop {
+        // Return from every function that has finished, a function can end with a call
+        while i == current_function.len() && current_function_name != "main" {
+            let (fname, index) = call_stack.pop().unwrap();
+            i = index + 1;
+            current_function_name = fname.clone();
+            current_function = program.get(&fname).unwrap();
+        }
+        if i >= current_function.len() {
+            break;
+        }
         match &current_function[i] {
             Function::Push(byte) => {
                 stack.push(*byte);
@@ -325,23 +1722,70 @@ fn simulate_program(program: Program) {
             Function::Plus() => {
                 let a = stack.pop();
                 let b = stack.pop();
-                stack.push(a + b);
+                stack.push(a.wrapping_add(b));
             }
             Function::Minus() => {
                 let b = stack.pop();
                 let a = stack.pop();
-                stack.push(a - b);
+                stack.push(a.wrapping_sub(b));
             }
             Function::Mult() => {
                 let b = stack.pop();
                 let a = stack.pop();
-                stack.push(a * b);
+                stack.push(a.wrapping_mul(b));
+            }
+            // Dividing by 0 gives a quotient of 0 and a remainder of a
+            Function::Div() => {
+                let b = stack.pop();
+                let a = stack.pop();
+                stack.push(a.checked_div(b).unwrap_or(0));
+            }
+            Function::Mod() => {
+                let b = stack.pop();
+                let a = stack.pop();
+                stack.push(a.checked_rem(b).unwrap_or(a));
+            }
+            Function::DivMod() => {
+                let b = stack.pop();
+                let a = stack.pop();
+                stack.push(a.checked_div(b).unwrap_or(0));
+                stack.push(a.checked_rem(b).unwrap_or(a));
             }
             Function::CharOut() => {
-                print!("{}", stack.pop() as char);
+                output.write_all(&[stack.pop()])?;
+                output_len += 1;
             }
             Function::NumOut() => {
-                print!("{}", stack.pop());
+                let number = stack.pop().to_string();
+                output.write_all(number.as_bytes())?;
+                output_len += number.len();
+            }
+            Function::CharIn() => {
+                stack.push(read_byte(input)?);
+            }
+            // Reads digits until any other byte (which is dropped) and wraps to 8 bits
+            Function::NumIn() => {
+                let mut number = 0u8;
+                let mut byte = read_byte(input)?;
+                while byte.is_ascii_digit() {
+                    number = number.wrapping_mul(10).wrapping_add(byte - b'0');
+                    byte = read_byte(input)?;
+                }
+                stack.push(number);
+            }
+            // Reads until a new line (which is dropped) and pushes the string in the same order
+            // as a string literal
+            Function::StringIn() => {
+                let mut line: Vec<u8> = vec![];
+                let mut byte = read_byte(input)?;
+                while byte != 0 && byte != b'\n' {
+                    line.push(byte);
+                    byte = read_byte(input)?;
+                }
+                stack.push(0u8);
+                for byte in line.iter().rev() {
+                    stack.push(*byte);
+                }
             }
             Function::Write() => {
                 let a = stack.pop();
@@ -450,7 +1894,7 @@ fn simulate_program(program: Program) {
 
                 stack.push(a & b);
             }
-            Function::FunctionDeclaration(_) => {
+            Function::FunctionDeclaration(_, _) | Function::Override() | Function::Private() => {
                 println!("This shouldn't be reachable");
             }
             // There's definitely a better way to do this
@@ -465,30 +1909,28 @@ fn simulate_program(program: Program) {
                 continue;
             }
             Function::StringLiteral(string_literal) => {
-                let byte_string = string_literal.as_bytes();
+                let byte_string = string_literal.as_slice();
                 stack.push(0u8);
                 for i in (0..byte_string.len()).rev() {
                     stack.push(byte_string[i]);
                 }
             }
-            Function::Import(_) => {
+            Function::Import(_, _) | Function::Variable(_, _) => {
                 eprintln!("Unreachable");
             }
         }
-        i += 1;
-        // and this as well
-        if i == current_function.len() && (&current_function_name != "main") {
-            let (fname, index) = call_stack.pop().unwrap();
-            i = index + 1;
-            current_function_name = fname.clone();
-            current_function = program.get(&fname).unwrap();
+        if is_traced(&current_function[i]) {
+            on_step(&current_function_name, i, &stack, output_len);
         }
+        i += 1;
     }
+    output.flush()?;
+    Ok((stack, memory))
 }
 
-fn change_extension_to_bf(filename: &str) -> String {
+fn change_extension(filename: &str, extension: &str) -> String {
     let mut file: PathBuf = PathBuf::from(filename);
-    file.set_extension("bf");
+    file.set_extension(extension);
     file.into_os_string().into_string().unwrap()
 }
 
@@ -513,7 +1955,9 @@ fn record_for_test() -> io::Result<()> {
 
 #[cfg(test)]
 mod test {
+    use super::temp_dir::TempDir;
     use super::*;
+    use std::collections::{HashMap, VecDeque};
     use std::path::PathBuf;
 
     // https://blog.v-gar.de/2019/04/rust-remove-trailing-newline-after-input/
@@ -523,6 +1967,15 @@ mod test {
         }
     }
 
+    fn compile_program_from_source(
+        filepath: &str,
+        source_code: String,
+    ) -> Result<String, Box<dyn Error>> {
+        let program = frontend::parse_program(filepath, &source_code, &[])?;
+        let bf_code = compile_program(program, &CompileOptions::default())?;
+        Ok(bf_code)
+    }
+
     fn make_code_for_comparison(
         validation_code_filepath: &str,
         file_to_compile: &str,
@@ -607,6 +2060,585 @@ mod test {
         assert!(test("while"));
     }
 
+    #[test]
+    fn less_than_op() {
+        assert!(test("lt"));
+    }
+
+    #[test]
+    fn greater_than_op() {
+        assert!(test("gt"));
+    }
+
+    // Compiles and runs 'a b operation', returning the stack and whether the cells above it were
+    // left cleared
+    fn run_binary_operation(a: u8, b: u8, operation: &Function) -> (Vec<u32>, bool) {
+        let mut functions = HashMap::new();
+        functions.insert(
+            String::from("main"),
+            vec![Function::Push(a), Function::Push(b), operation.clone()],
+        );
+        let program = Program::new(
+            VecDeque::new(),
+            functions,
+            vec![],
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+        );
+        let bf_code = compile_program(program, &CompileOptions::default()).unwrap();
+        let machine = bf::run(
+            &bf_code,
+            bf::Config::default(),
+            &mut io::empty(),
+            &mut vec![],
+        )
+        .unwrap();
+        let stack = machine.tape[1..=machine.pointer].to_vec();
+        let cleared = machine.tape[machine.pointer + 1..]
+            .iter()
+            .all(|cell| *cell == 0);
+        (stack, cleared)
+    }
+
+    const SAMPLE_VALUES: [u8; 12] = [0, 1, 2, 3, 7, 64, 127, 128, 129, 200, 254, 255];
+
+    #[test]
+    fn comparisons_match_simulator() {
+        for a in SAMPLE_VALUES {
+            for b in SAMPLE_VALUES {
+                for (comparison, expected) in [
+                    (Function::LessThan(), a < b),
+                    (Function::GreaterThan(), a > b),
+                ] {
+                    assert_eq!(
+                        run_binary_operation(a, b, &comparison),
+                        (vec![expected as u32], true),
+                        "{} {:?} {}",
+                        a,
+                        comparison,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn division_matches_simulator() {
+        for a in SAMPLE_VALUES {
+            for b in SAMPLE_VALUES {
+                let quotient = a.checked_div(b).unwrap_or(0) as u32;
+                let remainder = a.checked_rem(b).unwrap_or(a) as u32;
+                for (operation, expected) in [
+                    (Function::Div(), vec![quotient]),
+                    (Function::Mod(), vec![remainder]),
+                    (Function::DivMod(), vec![quotient, remainder]),
+                ] {
+                    assert_eq!(
+                        run_binary_operation(a, b, &operation),
+                        (expected, true),
+                        "{} {:?} {}",
+                        a,
+                        operation,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn division_op() {
+        assert!(test("div"));
+    }
+
+    #[test]
+    fn modulo_op() {
+        assert!(test("mod"));
+    }
+
+    #[test]
+    fn divmod_op() {
+        assert!(test("divmod"));
+    }
+
+    #[test]
+    fn input_op() {
+        assert!(test("input"));
+    }
+
+    #[test]
+    fn call_op() {
+        assert!(test("call"));
+    }
+
+    #[test]
+    fn recursion_needs_a_signature() {
+        let source = String::from("fn main\n 3 down\nend\nfn down\n dup if 1 - down end\nend");
+        assert!(compile_program_from_source("test", source).is_err());
+
+        let source = String::from(
+            "fn main\n 3 down\nend\nfn down ( n -- a b )\n dup if pop 1 - down end\nend",
+        );
+        assert!(compile_program_from_source("test", source).is_ok());
+    }
+
+    #[test]
+    fn stack_height_op() {
+        assert!(test("height"));
+    }
+
+    #[test]
+    fn stack_height_after_unbalanced_block() {
+        let source = String::from("fn main\n 1 if 2 end ?\nend");
+        assert!(compile_program_from_source("test", source).is_err());
+
+        let source = String::from("fn main\n 1 while 1 - 0 end ?\nend");
+        assert!(compile_program_from_source("test", source).is_err());
+
+        let source = String::from("fn main\n 1 if 2 pop else 3 pop end ?\nend");
+        assert!(compile_program_from_source("test", source).is_ok());
+    }
+
+    #[test]
+    fn variable_op() {
+        assert!(test("var"));
+    }
+
+    #[test]
+    fn import_op() {
+        assert!(test("import"));
+    }
+
+    #[test]
+    fn variables_must_fit_in_memory() {
+        let source = String::from("var a 200\nvar b 56\nfn main\n initmem a b -\nend");
+        assert!(compile_program_from_source("test", source).is_ok());
+
+        let source = String::from("var a 200\nvar b 57\nfn main\n initmem a b -\nend");
+        assert!(compile_program_from_source("test", source).is_err());
+
+        let source = String::from("var a\nvar a\nfn main\nend");
+        assert!(compile_program_from_source("test", source).is_err());
+    }
+
+    // The operations of main in a program read from source
+    fn parse_main(source: &str) -> Result<Vec<Function>, Box<dyn Error>> {
+        let program = frontend::parse_program("test", source, &[])?;
+        Ok(program.functions["main"].clone())
+    }
+
+    #[test]
+    fn string_escapes() {
+        let source = r#"fn main
+ "say \"hi\"\\ \n\t\0\x41\xff" "a b" // "c"
+end"#;
+        assert_eq!(
+            parse_main(source).unwrap(),
+            vec![
+                Function::StringLiteral(b"say \"hi\"\\ \n\t\0A\xff".to_vec()),
+                Function::StringLiteral(b"a b".to_vec()),
+            ]
+        );
+
+        for malformed in [r#""\q""#, r#""\x4""#, r#""\xzz""#, r#""abc"#, r#""abc\""#] {
+            let source = format!("fn main\n {}\nend", malformed);
+            assert!(parse_main(&source).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn character_literals() {
+        let source = r#"fn main
+ 'a' ' ' '\n' '\'' '"' '\x7f' chout
+end"#;
+        assert_eq!(
+            parse_main(source).unwrap(),
+            vec![
+                Function::Push(b'a'),
+                Function::Push(b' '),
+                Function::Push(b'\n'),
+                Function::Push(b'\''),
+                Function::Push(b'"'),
+                Function::Push(0x7f),
+                Function::CharOut(),
+            ]
+        );
+
+        for malformed in ["'ab'", "''", "'a", "'\\'", "'é'", "'\\q'"] {
+            let source = format!("fn main\n {}\nend", malformed);
+            assert!(parse_main(&source).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn number_literals() {
+        let source = "fn main\n 65 0x41 0XFF 0xff 0b1010 -1 -128 -0 007 - 2dup\nend";
+        assert_eq!(
+            parse_main(source).unwrap(),
+            vec![
+                Function::Push(65),
+                Function::Push(0x41),
+                Function::Push(255),
+                Function::Push(255),
+                Function::Push(10),
+                Function::Push(255),
+                Function::Push(128),
+                Function::Push(0),
+                Function::Push(7),
+                Function::Minus(),
+                Function::TwoDup(),
+            ]
+        );
+
+        for out_of_range in [
+            "256",
+            "300",
+            "-129",
+            "0x100",
+            "0b100000000",
+            "99999999999999999999",
+        ] {
+            let source = format!("fn main\n {}\nend", out_of_range);
+            assert!(parse_main(&source).is_err(), "{}", out_of_range);
+        }
+    }
+
+    #[test]
+    fn unknown_words_are_reported() {
+        let source = String::from("fn double\n dup +\nend\nfn main\n 1 double\nend");
+        assert!(compile_program_from_source("test", source).is_ok());
+
+        let source = String::from(
+            "var counter\nfn double\n dup +\nend\nfn main\n 1 doubel chuot\n counter2 frobnicate\nend",
+        );
+        let err = compile_program_from_source("test", source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:6:4: unknown word 'doubel'\n  = note: did you mean 'double'?\n\
+             test:6:11: unknown word 'chuot'\n  = note: did you mean 'chout'?\n\
+             test:7:2: unknown word 'counter2'\n  = note: did you mean 'counter'?\n\
+             test:7:11: unknown word 'frobnicate'"
+        );
+    }
+
+    // A single file parsed on its own, before its imports are read or anything is checked
+    fn parse_file(filepath: &str, source: &str) -> Result<Program, Box<dyn Error>> {
+        let tokens = phronima::tokenize_source_code(filepath, source);
+        phronima::parse_program_structure(phronima::parse_tokens(tokens)?)
+    }
+
+    #[test]
+    fn functions_are_defined_once() {
+        let err = parse_file("test", "fn f\nend\nfn f\nend").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:3:1: function 'f' is defined more than once"
+        );
+
+        let library = "fn greet\n 'l' chout\nend\nfn helper\nend";
+        let mut program = parse_file("main", "fn greet\n 'm' chout\nend").unwrap();
+        let err = program
+            .consume(parse_file("lib", library).unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lib:1:1: function 'greet' is defined more than once\n  \
+             = note: declare one of them with 'override fn' to replace the other"
+        );
+
+        // The override replaces the imported function whichever file is loaded first
+        for (first, second) in [("main", "lib"), ("lib", "main")] {
+            let source = |file| match file {
+                "main" => "override fn greet\n 'm' chout\nend\nfn main\n greet helper\nend",
+                _ => library,
+            };
+            let mut program = parse_file(first, source(first)).unwrap();
+            program
+                .consume(parse_file(second, source(second)).unwrap())
+                .unwrap();
+            assert_eq!(
+                program.functions["greet"],
+                vec![Function::Push(b'm'), Function::CharOut()]
+            );
+            assert_eq!(program.declarations["greet"].filepath, "main");
+            assert!(program.functions.contains_key("helper"));
+        }
+
+        let mut program = parse_file("main", "override fn greet\nend").unwrap();
+        let err = program
+            .consume(parse_file("lib", "override fn greet\nend").unwrap())
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'greet' is defined more than once")
+        );
+        assert!(parse_file("test", "override greet\nend").is_err());
+    }
+
+    #[test]
+    fn functions_return_after_a_last_call() {
+        // 'outer' ends with a call and 'empty' has no operations, both must return to main
+        let source = "fn inner\n 'b' chout\nend\nfn empty\nend\nfn outer\n 'a' chout inner\nend\n\
+                      fn main\n outer empty 'c' chout\nend";
+        let program = frontend::parse_program("test", source, &[]).unwrap();
+        let mut output = vec![];
+        simulate_program(program, &mut io::empty(), &mut output, &mut |_, _, _, _| {}).unwrap();
+        assert_eq!(output, b"abc");
+    }
+
+    #[test]
+    fn programs_must_have_a_main() {
+        let directory = TempDir::new("main");
+        directory.write("lib.phron", "fn helper\nend");
+        let path = &directory.display("lib.phron");
+        let options = CompileOptions::default();
+        let err = run_program_from_file(path, &[], bf::Config::default(), &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("could not find function main in {}", path)
+        );
+    }
+
+    #[test]
+    fn imports_are_found_relative_to_the_importer() {
+        let directory = TempDir::new("imports");
+        let files = [
+            (
+                "main.phron",
+                "import lib/greet.phron\nfn main\n greet extra\nend",
+            ),
+            (
+                "lib/greet.phron",
+                "import ../shared.phron\nimport extra.phron\nfn greet\n shared\nend",
+            ),
+            ("shared.phron", "fn shared\n 'a' chout\nend"),
+            ("path/extra.phron", "fn extra\n 'b' chout\nend"),
+        ];
+        for (name, source) in files {
+            directory.write(name, source);
+        }
+        let main = &directory.display("main.phron");
+
+        let err = frontend::read_program(main, &[]).unwrap_err();
+        assert!(
+            err.to_string().starts_with(&format!(
+                "{}:2:1: cannot find imported file 'extra.phron'",
+                directory.display("lib/greet.phron")
+            )),
+            "{}",
+            err
+        );
+
+        let (search_path, rest) =
+            read_search_path(&[String::from("-I"), directory.display("path")]).unwrap();
+        assert!(rest.is_empty());
+        let program = frontend::read_program(main, &search_path).unwrap();
+        let mut output: Vec<u8> = vec![];
+        simulate_program(program, &mut io::empty(), &mut output, &mut |_, _, _, _| {}).unwrap();
+        assert_eq!(output, b"ab");
+    }
+
+    #[test]
+    fn blocks_must_be_nested() {
+        let error = |source: &str| match parse_main(source) {
+            Ok(_) => String::from("no error"),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(
+            error("fn main\n 1 if 2 pop end\nend\nend"),
+            "test:4:1: 'end' without an open block"
+        );
+        assert_eq!(
+            error("fn main\n else\nend"),
+            "test:2:2: 'else' without a matching 'if'"
+        );
+        assert_eq!(
+            error("fn main\n 1 while else end\nend"),
+            "test:2:10: 'else' without a matching 'if'"
+        );
+        assert_eq!(
+            error("fn main\n 1 if 2 else 3 else 4 end\nend"),
+            "test:2:16: 'else' without a matching 'if'"
+        );
+        assert_eq!(
+            error("fn main\n 1 if\nfn helper\nend"),
+            "test:3:1: 'fn helper' is declared inside 'main'\n  \
+             = note: each 'fn' must be closed with 'end' before the next one"
+        );
+        assert_eq!(
+            error("fn main\n 1 while 1 - end\n 1 if pop"),
+            "test:3:4: 'if' is never closed\n  \
+             = note: every 'if', 'while' and 'fn' needs a matching 'end'"
+        );
+        assert!(error("fn main\n 1").starts_with("test:1:1: 'fn' is never closed"));
+    }
+
+    #[test]
+    fn errors_point_at_the_source() {
+        let render = |source: &str| {
+            let err = compile_program_from_source("test", source.to_string()).unwrap_err();
+            diagnostic::render_error(&*err, &|_| Some(source.to_string()))
+        };
+        assert_eq!(
+            render(
+                "fn main
+ 1 pull x
+end"
+            ),
+            "error: expected a number after 'pull', found 'x'\n \
+             --> test:2:9\n  \
+             |\n\
+             2 |  1 pull x\n  \
+             |         ^\n"
+        );
+        assert_eq!(
+            render("var count\nvar count\nfn main\nend"),
+            "error: variable 'count' is declared more than once\n \
+             --> test:2:1\n  \
+             |\n\
+             2 | var count\n  \
+             | ^^^\n \
+             --> test:1:1\n  \
+             |\n\
+             1 | var count\n  \
+             | --- first declared here\n"
+        );
+        assert!(render("var dup\nfn main\nend").starts_with(
+            "error: 'dup' is a built-in word and can't be used as a variable name\n \
+             --> test:1:5\n"
+        ));
+        // Errors found while generating code point at the source too
+        assert!(
+            render("fn main\n mem 1 write\nend")
+                .starts_with("error: the memory is used before it is initialized\n --> test:2:8\n")
+        );
+        assert!(
+            render("fn f\n ? if f end\nend\nfn main\n f\nend").starts_with(
+                "error: function 'f' is recursive, so it must declare a signature such as \
+             '( a -- b )' and can't use '?'\n --> test:2:7\n"
+            )
+        );
+        assert!(
+            render("fn show ( str -- )\n while chout end pop\nend\nfn main\n \"hi\" show ?\nend")
+                .starts_with(
+                    "error: the stack height used by '?' in 'main' is not known at compile time\n \
+                     --> test:5:12\n"
+                )
+        );
+        assert!(
+            render("fn main\nend\nend")
+                .starts_with("error: 'end' without an open block\n --> test:3:1\n")
+        );
+        assert!(render("fn main\n \"abc\nend").contains(" --> test:2:2\n"));
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        let source = "fn main\n mem 13 + 2 3 * 250 10 + 4 4 = 0 not dup 1 -\nend";
+        let mut program = frontend::parse_program("test", source, &[]).unwrap();
+        phronima::fold_constants(&mut program).unwrap();
+        assert_eq!(
+            program.functions["main"],
+            vec![
+                Function::Push(13),
+                Function::Push(6),
+                Function::Push(4),
+                Function::Push(1),
+                Function::Push(1),
+                Function::Dup(),
+                Function::Push(1),
+                Function::Minus(),
+            ]
+        );
+        // Each folded push is located at the first operation it was folded from
+        let locations = &program.locations["main"];
+        assert_eq!(locations.len(), 8);
+        assert_eq!(locations[0].to_string(), "test:2:2");
+        assert_eq!(locations[1].to_string(), "test:2:11");
+
+        // Nothing is folded across a block
+        let source = "fn main\n 1 2 if pop 3 end +\nend";
+        let mut program = frontend::parse_program("test", source, &[]).unwrap();
+        let operations = program.functions["main"].clone();
+        phronima::fold_constants(&mut program).unwrap();
+        assert_eq!(program.functions["main"], operations);
+
+        // Comparisons are folded, and blocks are linked to where they are after folding
+        let source =
+            "fn main\n 3 2 > 2 3 < + if 2 3 > pop else 4 5 * 1 while 1 - end pop pop end\nend";
+        let mut program = frontend::parse_program("test", source, &[]).unwrap();
+        phronima::fold_constants(&mut program).unwrap();
+        let source = "fn main\n 2 if 0 pop else 20 1 while 1 - end pop pop end\nend";
+        let folded = frontend::parse_program("test", source, &[]).unwrap();
+        assert_eq!(program.functions["main"], folded.functions["main"]);
+    }
+
+    // The arithmetic programs in tests/ compile to the push of their results when optimized
+    #[test]
+    fn arithmetic_programs_are_folded() {
+        let folded = [
+            ("add", "28"),
+            ("sub", "6"),
+            ("mult", "15 24"),
+            ("not", "0 1"),
+        ];
+        for (name, results) in folded {
+            let program = frontend::read_program(&format!("./tests/{}.phron", name), &[]).unwrap();
+            let source = format!("fn main\n {}\nend", results);
+            let pushes = frontend::parse_program("test", &source, &[]).unwrap();
+            let options = CompileOptions::default();
+            assert_eq!(
+                compile_program(program, &options).unwrap(),
+                compile_program(pushes, &options).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn pushed_bytes_are_short() {
+        for byte in 0..=255u8 {
+            let mut pushed = ir::Builder::new();
+            push_byte(&mut pushed, byte, Some(b'a'));
+            let pushed = ir::to_bf(&pushed.finish().unwrap());
+            let naive = (byte as usize).min(256 - byte as usize);
+            assert!(pushed.len() <= naive, "{}: {}", byte, pushed);
+
+            // Pushed above 'a' on the stack
+            let mut code = ir::Builder::new();
+            code.move_by(1);
+            push_byte(&mut code, b'a', None);
+            code.move_by(1);
+            push_byte(&mut code, byte, Some(b'a'));
+            let code = ir::to_bf(&code.finish().unwrap());
+            let machine = bf::run(
+                &code,
+                bf::Config::default(),
+                &mut "".as_bytes(),
+                &mut vec![],
+            )
+            .unwrap();
+            assert_eq!(machine.tape[1..4], [b'a' as u32, byte as u32, 0]);
+            assert_eq!(machine.pointer, 2);
+        }
+    }
+
+    #[test]
+    fn emit_c() {
+        let args = vec![String::from("--emit"), String::from("c")];
+        let options = read_compile_options(&args).unwrap();
+        let program = frontend::read_program("./tests/call.phron", &[]).unwrap();
+        let c_code = compile_program(program, &options).unwrap();
+        assert!(c_code.starts_with("#include <stdio.h>"));
+        assert!(c_code.contains("putchar(*p);"));
+
+        let args = vec![String::from("--emit"), String::from("js")];
+        assert!(read_compile_options(&args).is_err());
+    }
+
     #[test]
     fn swap_op() {
         assert!(test("swap"));
@@ -622,8 +2654,266 @@ mod test {
         assert!(test("else"));
     }
 
+    #[test]
+    fn blocks_op() {
+        assert!(test("blocks"));
+    }
+
     #[test]
     fn not_op() {
         assert!(test("not"));
     }
 }
+
+// Differential tests: every program in tests/ and examples/ is simulated, then compiled and run on
+// the brainf*ck interpreter. Both must write the same output and finish with the same stack and
+// memory. A program the compiler rejects is a failure too
+#[cfg(test)]
+mod differential_test {
+    use super::*;
+
+    fn simulator_stack(stack: &Stack) -> Vec<u8> {
+        stack.data[1..=stack.top].to_vec()
+    }
+
+    fn machine_stack(machine: &bf::Machine) -> Vec<u8> {
+        machine.tape[1..=machine.pointer]
+            .iter()
+            .map(|cell| *cell as u8)
+            .collect()
+    }
+
+    fn machine_memory(machine: &bf::Machine) -> Vec<u8> {
+        (0..256)
+            .map(|address| machine.tape[MEMORY_START + (address + 1) * MEMORY_CELL_SIZE] as u8)
+            .collect()
+    }
+
+    // Runs the traced versions of the program and reports the first operation after which the
+    // stack or output differ
+    fn find_divergence(program: Program, input: &[u8]) -> String {
+        let functions = program.functions.clone();
+
+        let mut simulator_trace: Vec<(String, usize, Vec<u8>, usize)> = vec![];
+        let _ = simulate_program(
+            program.clone(),
+            &mut &input[..],
+            &mut vec![],
+            &mut |function_name, index, stack, output_len| {
+                simulator_trace.push((
+                    function_name.to_string(),
+                    index,
+                    simulator_stack(stack),
+                    output_len,
+                ));
+            },
+        );
+
+        let bf_code = compile_program(
+            program,
+            &CompileOptions {
+                trace: true,
+                ..CompileOptions::default()
+            },
+        )
+        .unwrap();
+        let code = bf::Code::parse(&bf_code).unwrap();
+        let mut machine = bf::Machine::new(bf::Config::default());
+        let mut machine_trace: Vec<(Vec<u8>, usize)> = vec![];
+        let _ = machine.execute_with_breakpoints(&code, &mut &input[..], &mut vec![], |machine| {
+            machine_trace.push((machine_stack(machine), machine.output_len));
+        });
+
+        for (step, (function_name, index, stack, output_len)) in simulator_trace.iter().enumerate()
+        {
+            let machine_step = machine_trace.get(step);
+            if machine_step != Some(&(stack.clone(), *output_len)) {
+                return format!(
+                    "first diverging operation: {:?} ({} #{})\n    sim: stack {:?}, {} bytes written\n    bf:  {:?}",
+                    functions[function_name][*index],
+                    function_name,
+                    index,
+                    stack,
+                    output_len,
+                    machine_step
+                );
+            }
+        }
+        String::from("traces match, the difference is in the final state")
+    }
+
+    // Both are given the contents of the file with the extension 'in' as input, if there is one
+    fn compare_with_simulator(filepath: &str, options: &CompileOptions) -> Result<(), String> {
+        let program = frontend::read_program(filepath, &[]).unwrap();
+        let mut input_path = PathBuf::from(filepath);
+        input_path.set_extension("in");
+        let input = fs::read(input_path).unwrap_or_default();
+
+        let mut simulator_output: Vec<u8> = vec![];
+        let (stack, memory) = simulate_program(
+            program.clone(),
+            &mut &input[..],
+            &mut simulator_output,
+            &mut |_, _, _, _| {},
+        )
+        .unwrap();
+
+        let optimized = if options.optimize {
+            ""
+        } else {
+            " (not optimized)"
+        };
+        let bf_code = compile_program(program.clone(), options)
+            .map_err(|err| format!("{}{}\n  rejected: {}", filepath, optimized, err))?;
+        let mut machine_output: Vec<u8> = vec![];
+        let machine = bf::run(
+            &bf_code,
+            bf::Config::default(),
+            &mut &input[..],
+            &mut machine_output,
+        )
+        .unwrap();
+
+        let mut differences: Vec<String> = vec![];
+        if simulator_output != machine_output {
+            differences.push(format!(
+                "output: sim {:?}, bf {:?}",
+                String::from_utf8_lossy(&simulator_output),
+                String::from_utf8_lossy(&machine_output)
+            ));
+        }
+        if simulator_stack(&stack) != machine_stack(&machine) {
+            differences.push(format!(
+                "stack: sim {:?}, bf {:?}",
+                simulator_stack(&stack),
+                machine_stack(&machine)
+            ));
+        }
+        if memory.to_vec() != machine_memory(&machine) {
+            differences.push(String::from("memory differs"));
+        }
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            differences.push(find_divergence(program, &input));
+            Err(format!(
+                "{}{}\n  {}",
+                filepath,
+                optimized,
+                differences.join("\n  ")
+            ))
+        }
+    }
+
+    // Every program is compiled with and without optimization, to show the optimizer doesn't
+    // change what the program does
+    fn compare_directory(directory: &str) {
+        let mut failures: Vec<String> = vec![];
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "phron")
+            {
+                continue;
+            }
+            for optimize in [true, false] {
+                let options = CompileOptions {
+                    optimize,
+                    ..CompileOptions::default()
+                };
+                if let Err(failure) = compare_with_simulator(path.to_str().unwrap(), &options) {
+                    failures.push(failure);
+                }
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_programs() {
+        compare_directory("./tests/");
+    }
+
+    #[test]
+    fn example_programs() {
+        compare_directory("./examples/");
+    }
+
+    // Pushed bytes can't rely on cells wrapping at 8 bits when the cells are wider
+    #[test]
+    fn wide_cells() {
+        let source = "fn print ( str -- )\n while chout end pop\nend\n\
+                      fn main\n 200 numout 10 chout 255 numout 10 chout 'z' chout 129 numout\n \
+                      \"Hello, world!\\n\" print\nend\n";
+        let program = frontend::parse_program("test", source, &[]).unwrap();
+
+        let mut simulator_output: Vec<u8> = vec![];
+        simulate_program(
+            program.clone(),
+            &mut io::empty(),
+            &mut simulator_output,
+            &mut |_, _, _, _| {},
+        )
+        .unwrap();
+
+        for optimize in [true, false] {
+            let options = CompileOptions {
+                optimize,
+                cell_width: bf::CellWidth::U16,
+                ..CompileOptions::default()
+            };
+            let bf_code = compile_program(program.clone(), &options).unwrap();
+            let config = bf::Config {
+                cell_width: bf::CellWidth::U16,
+                ..bf::Config::default()
+            };
+            let mut machine_output: Vec<u8> = vec![];
+            bf::run(&bf_code, config, &mut io::empty(), &mut machine_output).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&simulator_output),
+                String::from_utf8_lossy(&machine_output)
+            );
+        }
+    }
+
+    // More segments than a single cell can count down, spread over recursion and many call sites
+    #[test]
+    fn many_call_sites() {
+        let mut source = String::from(
+            "fn down ( n -- )\n dup if dup numout 1 - down 0 end pop pop\nend\n\
+             fn a\n 'a' chout\nend\nfn b ( x -- x )\n 1 +\nend\nfn main\n 0\n",
+        );
+        for i in 0..300 {
+            source.push_str(if i % 3 == 0 { " a\n" } else { " b\n" });
+        }
+        source.push_str(" numout 3 down\nend\n");
+        let program = frontend::parse_program("test", &source, &[]).unwrap();
+
+        let mut simulator_output: Vec<u8> = vec![];
+        simulate_program(
+            program.clone(),
+            &mut io::empty(),
+            &mut simulator_output,
+            &mut |_, _, _, _| {},
+        )
+        .unwrap();
+
+        for optimize in [true, false] {
+            let options = CompileOptions {
+                optimize,
+                ..CompileOptions::default()
+            };
+            let bf_code = compile_program(program.clone(), &options).unwrap();
+            let mut machine_output: Vec<u8> = vec![];
+            bf::run(
+                &bf_code,
+                bf::Config::default(),
+                &mut io::empty(),
+                &mut machine_output,
+            )
+            .unwrap();
+            assert_eq!(simulator_output, machine_output);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// A directory for the files of one test, removed when it is dropped so a failing test doesn't
+// leave it behind
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    // The name keeps tests that run at the same time out of each other's files
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("phronima-{}-{}", name, process::id()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    // Writes the file, creating the directories it is in
+    pub fn write(&self, name: &str, source: &str) {
+        let path = self.path.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+
+    // The path of the file as it is shown in errors
+    pub fn display(&self, name: &str) -> String {
+        self.path.join(name).display().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}